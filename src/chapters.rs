@@ -0,0 +1,102 @@
+use crate::segment::{Segment, SegmentSource};
+use gstreamer::{TagList, TagMergeMode, TocEntryType, TocRef, tags};
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct Chapter {
+	pub title: Option<String>,
+	pub start: Duration,
+	/// `None` if unknown, the chapter ends where the next one starts then
+	pub end: Option<Duration>,
+}
+
+/// The top-level chapters of `toc`, the chapters of the first edition if there are editions
+pub fn from_toc(toc: &TocRef) -> Vec<Chapter> {
+	let mut entries = toc.entries();
+	if let Some(edition) = entries
+		.iter()
+		.find(|entry| entry.entry_type() == TocEntryType::Edition)
+	{
+		entries = edition.sub_entries();
+	}
+
+	let mut chapters: Vec<_> = entries
+		.iter()
+		.filter(|entry| entry.entry_type() == TocEntryType::Chapter)
+		.filter_map(|entry| {
+			let (start, stop) = entry.start_stop_times()?;
+			let to_duration = |time: i64| u64::try_from(time).ok().map(Duration::from_nanos);
+			Some(Chapter {
+				title: entry.tags().and_then(|tags| {
+					tags.get::<tags::Title>()
+						.map(|title| title.get().to_string())
+				}),
+				start: to_duration(start)?,
+				end: to_duration(stop),
+			})
+		})
+		.collect();
+	chapters.sort_by_key(|chapter| chapter.start);
+
+	chapters
+}
+
+/// Chapters as vorbis comments ("CHAPTER001=00:00:00.000" and "CHAPTER001NAME=Title"), the way
+/// they are stored in Ogg and FLAC files
+pub fn comments(chapters: &[Chapter]) -> TagList {
+	let mut tag_list = TagList::new();
+	{
+		let tag_list = tag_list.make_mut();
+		for (idx, chapter) in chapters.iter().enumerate() {
+			let millis = chapter.start.as_millis();
+			let comment = format!(
+				"CHAPTER{:03}={:02}:{:02}:{:02}.{:03}",
+				idx + 1,
+				millis / 3_600_000,
+				millis / 60_000 % 60,
+				millis / 1_000 % 60,
+				millis % 1_000
+			);
+			tag_list.add::<tags::ExtendedComment>(&comment.as_str(), TagMergeMode::Append);
+
+			if let Some(title) = &chapter.title {
+				let comment = format!("CHAPTER{:03}NAME={}", idx + 1, title);
+				tag_list.add::<tags::ExtendedComment>(&comment.as_str(), TagMergeMode::Append);
+			}
+		}
+	}
+	tag_list
+}
+
+/// One segment per chapter, numbered in order, the tags of the file are kept apart from the title
+/// and track number
+pub fn segments(chapters: &[Chapter]) -> Vec<Segment> {
+	chapters
+		.iter()
+		.enumerate()
+		.map(|(idx, chapter)| {
+			let number = idx as u32 + 1;
+
+			let mut tag_list = TagList::new();
+			{
+				let tag_list = tag_list.make_mut();
+				if let Some(title) = &chapter.title {
+					tag_list.add::<tags::Title>(&title.as_str(), TagMergeMode::Replace);
+				}
+				tag_list.add::<tags::TrackNumber>(&number, TagMergeMode::Replace);
+				tag_list.add::<tags::TrackCount>(&(chapters.len() as u32), TagMergeMode::Replace);
+			}
+
+			Segment {
+				source: SegmentSource::Chapters,
+				number,
+				title: chapter.title.clone(),
+				start: chapter.start,
+				end: chapter
+					.end
+					.or_else(|| chapters.get(idx + 1).map(|next| next.start)),
+				tags: tag_list,
+			}
+		})
+		.collect()
+}
@@ -2,27 +2,308 @@ use anyhow::{Context, Error, Result};
 use clap::{ArgAction, builder::ValueParser};
 use globset::GlobBuilder;
 use regex::bytes::{Regex, RegexBuilder};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::{
+	collections::{BTreeMap, HashMap},
+	fmt,
 	io::Write,
+	num::NonZeroUsize,
 	path::{Path, PathBuf},
+	time::Duration,
 };
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Config {
 	pub from: PathBuf,
+	/// the outputs produced from "from", a single unnamed one if no targets are configured
+	pub targets: Vec<Target>,
+	/// paths that are skipped, dirs are matched with a trailing "/"
+	pub exclude: Vec<Regex>,
+	pub jobs: Option<usize>,
+	/// how often a task is retried after a transient error
+	pub retries: u32,
+	/// abort the run after this many failed tasks
+	pub max_errors: Option<usize>,
+	/// longest time a single file may take to convert
+	pub timeout: Option<Duration>,
+	/// longest time a conversion may not make any progress
+	pub stall_timeout: Option<Duration>,
+	pub watch: bool,
+	/// only print what would be converted
+	pub plan: bool,
+	pub progress: Option<Progress>,
+	pub events: Option<EventsOutput>,
+	pub log: LogConfig,
+	pub walk: WalkConfig,
+	/// playlists are only converted if set
+	pub playlists: Option<PlaylistConfig>,
+}
+
+/// How playlists are written into the "to" dirs
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct PlaylistConfig {
+	#[serde(default)]
+	pub paths: PlaylistPaths,
+
+	#[serde(default)]
+	pub separator: PathSeparator,
+
+	/// only applies to ".m3u" playlists, ".m3u8" ones are always UTF-8
+	#[serde(default)]
+	pub encoding: PlaylistEncoding,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+pub enum PlaylistPaths {
+	/// relative to the dir of the playlist
+	#[default]
+	#[serde(rename = "relative")]
+	Relative,
+
+	#[serde(rename = "absolute")]
+	Absolute,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+pub enum PathSeparator {
+	/// the one of the current platform
+	#[default]
+	#[serde(rename = "native")]
+	Native,
+
+	#[serde(rename = "slash")]
+	Slash,
+
+	#[serde(rename = "backslash")]
+	Backslash,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+pub enum PlaylistEncoding {
+	#[default]
+	#[serde(rename = "utf8")]
+	Utf8,
+
+	/// characters that can't be represented are replaced with "?"
+	#[serde(rename = "latin1")]
+	Latin1,
+}
+
+/// Options for walking the "from" directory
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct WalkConfig {
+	/// symlink loops are reported as errors
+	#[serde(default)]
+	pub follow_symlinks: bool,
+
+	/// skip files and dirs starting with a "."
+	#[serde(default)]
+	pub skip_hidden: bool,
+
+	/// don't descend into dirs on other filesystems
+	#[serde(default)]
+	pub same_file_system: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct LogConfig {
+	pub path: PathBuf,
+	pub format: LogFormat,
+	pub mode: LogMode,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub enum LogFormat {
+	/// human readable error chains
+	#[default]
+	#[serde(rename = "text")]
+	Text,
+
+	/// one JSON object per line
+	#[serde(rename = "json")]
+	Json,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub enum LogMode {
+	#[default]
+	#[serde(rename = "append")]
+	Append,
+
+	/// start with an empty log on every run
+	#[serde(rename = "truncate")]
+	Truncate,
+
+	/// move the logs of previous runs to "<path>.1", "<path>.2" and so on
+	#[serde(rename = "rotate")]
+	Rotate,
+}
+
+#[derive(Clone, Debug)]
+pub enum EventsOutput {
+	Stdout,
+	/// a file or a FIFO
+	File(PathBuf),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Progress {
+	/// interactive terminal ui
+	Tui,
+	/// one line per event, for logs and terminals without cursor control
+	Plain,
+	None,
+}
+
+/// Output directory with its own matches
+#[derive(Clone, Debug)]
+pub struct Target {
+	/// `None` for the target of the top-level "to"
+	pub name: Option<String>,
 	pub to: PathBuf,
 	pub matches: Vec<TranscodeMatch>,
-	pub jobs: Option<usize>,
+	/// replace characters in file names that aren't allowed on FAT filesystems
+	pub fat_safe_names: bool,
 }
 
+/// Name of the per dir config files
+pub const DIR_CONFIG_FILE_NAME: &str = ".audio-conv.yaml";
+
 #[derive(Debug)]
+pub struct DirConfig {
+	pub matches: Vec<TranscodeMatch>,
+	/// matches replacing `matches` for single targets, by target name
+	pub targets: HashMap<String, Vec<TranscodeMatch>>,
+	/// whether the matches of the parent dirs apply if none of `matches` does
+	pub inherit: bool,
+}
+
+#[derive(Clone, Debug)]
 pub struct TranscodeMatch {
 	pub regexes: Vec<Regex>,
+	/// files that aren't handled by this match, even if they match `regexes`
+	pub exclude: Vec<Regex>,
+	/// properties of the audio stream the file needs to have, read by probing the file
+	pub source: Option<SourceCondition>,
+	/// conditions on the tags of the file, by tag name
+	pub tags: Vec<(String, TagCondition)>,
+	pub lossy_source: LossySource,
+	/// how files are copied if `lossy_source` copies them
+	pub copy_method: CopyMethod,
+	/// matching files aren't converted
+	pub skip: bool,
+	pub split_cue: SplitCue,
+	/// files without a CUE sheet are split into one output per chapter
+	pub split_chapters: bool,
+	pub stream: StreamSelection,
+	/// the files are videos, only their audio gets converted
+	pub video_audio: bool,
 	pub to: Transcode,
 }
 
+/// Whether files are split into one output per track of a CUE sheet
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Hash)]
+pub enum SplitCue {
+	#[default]
+	#[serde(rename = "off")]
+	Off,
+
+	/// using a ".cue" file next to the file
+	#[serde(rename = "sidecar")]
+	Sidecar,
+
+	/// using a ".cue" file next to the file, or the "CUESHEET" tag of the file
+	#[serde(rename = "embedded")]
+	Embedded,
+}
+
+/// Which of the audio streams of a file get converted
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamSelection {
+	#[default]
+	First,
+
+	/// the audio stream with the given number, starting at 1
+	Index(NonZeroUsize),
+
+	/// the first audio stream with the given language code, like "en" or "eng", the first audio
+	/// stream if none has it
+	Language(String),
+
+	/// every audio stream into an output of its own, with the stream number added to the name
+	All,
+}
+
+/// Condition on the values of a tag, all of the given predicates need to be satisfied
+#[derive(Clone, Debug)]
+pub struct TagCondition {
+	/// any value equals this one, ignoring case
+	pub equals: Option<String>,
+	/// any value matches this regex
+	pub regex: Option<Regex>,
+	/// whether the tag is set at all
+	pub exists: Option<bool>,
+}
+
+/// What to do with files using a lossy codec if `to` is lossy as well
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+pub enum LossySource {
+	#[default]
+	#[serde(rename = "transcode")]
+	Transcode,
+
+	/// copy the file as it is
+	#[serde(rename = "copy")]
+	Copy,
+
+	/// don't convert the file at all
+	#[serde(rename = "skip")]
+	Skip,
+
+	/// transcode if the bitrate of `to` is lower than the one of the file, copy it otherwise
+	#[serde(rename = "only_if_target_bitrate_lower")]
+	OnlyIfTargetBitrateLower,
+}
+
+/// Conditions on the audio stream of a file, all of them need to be satisfied
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SourceCondition {
+	/// codec names like "mp3", "aac", "flac", "opus", "vorbis" or "pcm", any of them matches
+	#[serde(default)]
+	pub codec: Vec<String>,
+
+	pub lossless: Option<bool>,
+
+	/// in Hz
+	pub sample_rate: Option<ValueRange<u32>>,
+
+	/// only known for lossless codecs
+	pub bit_depth: Option<ValueRange<u32>>,
+
+	pub channels: Option<ValueRange<u32>>,
+
+	/// in kbit/s
+	pub bitrate: Option<ValueRange<u32>>,
+
+	/// in seconds
+	pub duration: Option<ValueRange<f64>>,
+}
+
+/// Inclusive range, either bound may be left out
 #[derive(Clone, Debug, Deserialize)]
+pub struct ValueRange<T> {
+	pub min: Option<T>,
+	pub max: Option<T>,
+}
+
+impl<T: PartialOrd + Copy> ValueRange<T> {
+	pub fn contains(&self, value: T) -> bool {
+		self.min.is_none_or(|min| value >= min) && self.max.is_none_or(|max| value <= max)
+	}
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(tag = "codec")]
 pub enum Transcode {
 	#[serde(rename = "opus")]
@@ -50,20 +331,95 @@ pub enum Transcode {
 	},
 
 	#[serde(rename = "copy")]
-	Copy,
+	Copy {
+		#[serde(default)]
+		method: CopyMethod,
+	},
+}
+
+impl fmt::Display for Transcode {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Transcode::Opus {
+				bitrate,
+				bitrate_type,
+			} => write!(f, "opus {} kbit/s {}", bitrate, bitrate_type),
+			Transcode::Flac { compression } => write!(f, "flac compression {}", compression),
+			Transcode::Mp3 {
+				bitrate,
+				bitrate_type,
+			} => write!(f, "mp3 {} kbit/s {}", bitrate, bitrate_type),
+			Transcode::Copy { method } => write!(f, "copy ({})", method),
+		}
+	}
 }
 
 impl Transcode {
-	pub fn extension(&self) -> &'static str {
+	pub fn codec_name(&self) -> &'static str {
 		match self {
 			Transcode::Opus { .. } => "opus",
 			Transcode::Flac { .. } => "flac",
 			Transcode::Mp3 { .. } => "mp3",
-			Transcode::Copy => "",
+			Transcode::Copy { .. } => "copy",
+		}
+	}
+
+	/// Bitrate in kbit/s, `None` for lossless codecs and copies
+	pub fn bitrate(&self) -> Option<u16> {
+		match self {
+			Transcode::Opus { bitrate, .. } | Transcode::Mp3 { bitrate, .. } => Some(*bitrate),
+			Transcode::Flac { .. } | Transcode::Copy { .. } => None,
+		}
+	}
+
+	/// File extension of the output, `None` if the original file name should be kept
+	pub fn extension(&self) -> Option<&'static str> {
+		match self {
+			Transcode::Opus { .. } => Some("opus"),
+			Transcode::Flac { .. } => Some("flac"),
+			Transcode::Mp3 { .. } => Some("mp3"),
+			Transcode::Copy { .. } => None,
 		}
 	}
 }
 
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub enum CopyMethod {
+	/// regular copy of the file contents
+	#[default]
+	#[serde(rename = "copy")]
+	Copy,
+
+	#[serde(rename = "hardlink")]
+	Hardlink,
+
+	/// copy-on-write clone of the file, falls back to a regular copy if the filesystem doesn't
+	/// support it
+	#[serde(rename = "reflink")]
+	Reflink,
+
+	/// symlink with an absolute path to the "from" file
+	#[serde(rename = "symlink")]
+	Symlink,
+
+	/// symlink with a path relative to the "to" file
+	#[serde(rename = "symlink_relative")]
+	SymlinkRelative,
+}
+
+impl fmt::Display for CopyMethod {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let name = match self {
+			CopyMethod::Copy => "copy",
+			CopyMethod::Hardlink => "hardlink",
+			CopyMethod::Reflink => "reflink",
+			CopyMethod::Symlink => "symlink",
+			CopyMethod::SymlinkRelative => "symlink_relative",
+		};
+		write!(f, "{}", name)
+	}
+}
+
 fn default_opus_bitrate() -> u16 {
 	160
 }
@@ -89,7 +445,7 @@ impl Default for Transcode {
 	}
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum BitrateType {
 	#[serde(rename = "cbr")]
 	Cbr,
@@ -97,6 +453,15 @@ pub enum BitrateType {
 	Vbr,
 }
 
+impl fmt::Display for BitrateType {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			BitrateType::Cbr => write!(f, "cbr"),
+			BitrateType::Vbr => write!(f, "vbr"),
+		}
+	}
+}
+
 #[derive(Debug, Default, Deserialize)]
 struct ConfigFile {
 	from: Option<PathBuf>,
@@ -105,7 +470,55 @@ struct ConfigFile {
 	#[serde(default)]
 	matches: Vec<TranscodeMatchFile>,
 
+	#[serde(default)]
+	fat_safe_names: bool,
+
+	#[serde(default)]
+	targets: Vec<TargetFile>,
+
+	#[serde(default)]
+	exclude: Vec<ExcludeFile>,
+
 	jobs: Option<usize>,
+
+	retries: Option<u32>,
+	max_errors: Option<usize>,
+
+	#[serde(default)]
+	fail_fast: bool,
+
+	/// in seconds
+	timeout: Option<u64>,
+	/// in seconds, `0` disables the stall detection
+	stall_timeout: Option<u64>,
+
+	#[serde(default)]
+	log: LogConfigFile,
+
+	#[serde(default)]
+	walk: WalkConfig,
+
+	playlists: Option<PlaylistConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TargetFile {
+	name: String,
+	to: PathBuf,
+
+	/// the top-level matches are used if empty
+	#[serde(default)]
+	matches: Vec<TranscodeMatchFile>,
+
+	#[serde(default)]
+	fat_safe_names: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct LogConfigFile {
+	path: Option<PathBuf>,
+	format: Option<LogFormat>,
+	mode: Option<LogMode>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -116,7 +529,72 @@ struct TranscodeMatchFile {
 	#[serde(default)]
 	extensions: Vec<String>,
 
-	to: Transcode,
+	#[serde(default)]
+	exclude: Vec<ExcludeFile>,
+
+	source: Option<SourceCondition>,
+
+	#[serde(default)]
+	tags: BTreeMap<String, TagConditionFile>,
+
+	#[serde(default)]
+	lossy_source: LossySource,
+
+	#[serde(default)]
+	copy_method: CopyMethod,
+
+	#[serde(default)]
+	skip: bool,
+
+	#[serde(default)]
+	split_cue: SplitCue,
+
+	#[serde(default)]
+	split_chapters: bool,
+
+	#[serde(default)]
+	stream: StreamSelection,
+
+	#[serde(default)]
+	video_audio: bool,
+
+	/// only optional for matches that skip the files
+	to: Option<Transcode>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TagConditionFile {
+	equals: Option<String>,
+	regex: Option<String>,
+	exists: Option<bool>,
+}
+
+/// Config file in a sub dir of "from", applying to the dir and its sub dirs
+#[derive(Debug, Deserialize)]
+struct DirConfigFile {
+	#[serde(default)]
+	matches: Vec<TranscodeMatchFile>,
+
+	#[serde(default)]
+	targets: HashMap<String, DirConfigTargetFile>,
+
+	#[serde(default = "default_inherit")]
+	inherit: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct DirConfigTargetFile {
+	matches: Vec<TranscodeMatchFile>,
+}
+
+fn default_inherit() -> bool {
+	true
+}
+
+#[derive(Debug, Deserialize)]
+struct ExcludeFile {
+	glob: Option<String>,
+	regex: Option<String>,
 }
 
 pub fn config() -> Result<Config> {
@@ -161,7 +639,134 @@ pub fn config() -> Result<Config> {
 				.action(ArgAction::Set)
 				.help("Allow N jobs/transcodes at once. Defaults to number of logical cores"),
 		)
+		.arg(
+			Arg::new("retries")
+				.long("retries")
+				.required(false)
+				.value_parser(clap::value_parser!(u32))
+				.action(ArgAction::Set)
+				.help("Retry a file N times after transient (I/O) errors, with increasing delays"),
+		)
+		.arg(
+			Arg::new("max-errors")
+				.long("max-errors")
+				.required(false)
+				.value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(1..))
+				.action(ArgAction::Set)
+				.help("Abort after N files failed"),
+		)
+		.arg(
+			Arg::new("fail-fast")
+				.long("fail-fast")
+				.required(false)
+				.conflicts_with("max-errors")
+				.action(ArgAction::SetTrue)
+				.help("Abort after the first failed file, same as \"--max-errors=1\""),
+		)
+		.arg(
+			Arg::new("timeout")
+				.long("timeout")
+				.required(false)
+				.value_parser(clap::value_parser!(u64))
+				.action(ArgAction::Set)
+				.help("Abort the conversion of a file after N seconds"),
+		)
+		.arg(
+			Arg::new("stall-timeout")
+				.long("stall-timeout")
+				.required(false)
+				.value_parser(clap::value_parser!(u64))
+				.action(ArgAction::Set)
+				.help(
+					"Abort the conversion of a file if it didn't make any progress for N seconds, 0 \
+					 disables it",
+				),
+		)
+		.arg(
+			Arg::new("progress")
+				.long("progress")
+				.required(false)
+				.value_parser(["tui", "plain", "none"])
+				.action(ArgAction::Set)
+				.help(
+					"How to display the progress. Defaults to \"tui\" if stdout is a terminal, \
+					 \"plain\" otherwise",
+				),
+		)
+		.arg(
+			Arg::new("events")
+				.long("events")
+				.required(false)
+				.value_parser(["json"])
+				.action(ArgAction::Set)
+				.help("Emit a machine-readable event stream in the given format"),
+		)
+		.arg(
+			Arg::new("events-output")
+				.long("events-output")
+				.required(false)
+				.requires("events")
+				.value_parser(ValueParser::path_buf())
+				.action(ArgAction::Set)
+				.help("File or FIFO the events are written to, defaults to stdout"),
+		)
+		.arg(
+			Arg::new("log")
+				.long("log")
+				.required(false)
+				.value_parser(ValueParser::path_buf())
+				.action(ArgAction::Set)
+				.help(
+					"Path to the error log file, defaults to \"audio-conv.log\" in the current \
+					 directory",
+				),
+		)
+		.arg(
+			Arg::new("log-format")
+				.long("log-format")
+				.required(false)
+				.value_parser(["text", "json"])
+				.action(ArgAction::Set)
+				.help("Format of the error log, defaults to \"text\""),
+		)
+		.arg(
+			Arg::new("log-mode")
+				.long("log-mode")
+				.required(false)
+				.value_parser(["append", "truncate", "rotate"])
+				.action(ArgAction::Set)
+				.help("What to do with the error log of previous runs, defaults to \"append\""),
+		)
+		.arg(
+			Arg::new("follow-symlinks")
+				.long("follow-symlinks")
+				.required(false)
+				.action(ArgAction::SetTrue)
+				.help("Follow symlinks in the \"from\" directory"),
+		)
+		.arg(
+			Arg::new("skip-hidden")
+				.long("skip-hidden")
+				.required(false)
+				.action(ArgAction::SetTrue)
+				.help("Skip files and directories starting with a \".\""),
+		)
+		.arg(
+			Arg::new("same-file-system")
+				.long("same-file-system")
+				.required(false)
+				.action(ArgAction::SetTrue)
+				.help("Don't descend into directories on other filesystems"),
+		)
 		.subcommand(Command::new("init").about("writes an example config"))
+		.subcommand(
+			Command::new("plan")
+				.about("prints the files that would be converted and the rules applying to them"),
+		)
+		.subcommand(
+			Command::new("watch")
+				.about("converts all files and then keeps watching \"from\" for changes"),
+		)
 		.get_matches();
 
 	let current_dir = std::env::current_dir().context("Could not get current directory")?;
@@ -198,71 +803,147 @@ pub fn config() -> Result<Config> {
 		)));
 	}
 
-	let default_regex = RegexBuilder::new("\\.(flac|wav)$")
-		.case_insensitive(true)
-		.build()
-		.expect("Failed compiling default match regex");
+	let events = arg_matches.get_one::<String>("events").map(|_| {
+		match arg_matches.get_one::<PathBuf>("events-output") {
+			Some(path) if path.as_os_str() != "-" => EventsOutput::File(current_dir.join(path)),
+			_ => EventsOutput::Stdout,
+		}
+	});
+
+	let progress =
+		arg_matches
+			.get_one::<String>("progress")
+			.map(|progress| match progress.as_str() {
+				"tui" => Progress::Tui,
+				"plain" => Progress::Plain,
+				_ => Progress::None,
+			});
+
+	// the event stream and the progress display can't share stdout
+	let progress = match (&events, progress) {
+		(Some(EventsOutput::Stdout), Some(Progress::Tui | Progress::Plain)) => {
+			return Err(Error::msg(
+				"Events written to stdout can only be combined with \"--progress=none\"",
+			));
+		}
+		(Some(EventsOutput::Stdout), None) => Some(Progress::None),
+		(_, progress) => progress,
+	};
+
+	let config_log = config_file.as_ref().map(|c| &c.log);
+	let log = LogConfig {
+		path: arg_matches
+			.get_one::<PathBuf>("log")
+			.map(|p| current_dir.join(p))
+			.or_else(|| {
+				config_log
+					.and_then(|log| log.path.as_ref())
+					.map(|p| config_dir.join(p))
+			})
+			.unwrap_or_else(|| current_dir.join("audio-conv.log")),
+		format: arg_matches
+			.get_one::<String>("log-format")
+			.map(|format| match format.as_str() {
+				"json" => LogFormat::Json,
+				_ => LogFormat::Text,
+			})
+			.or_else(|| config_log.and_then(|log| log.format))
+			.unwrap_or_default(),
+		mode: arg_matches
+			.get_one::<String>("log-mode")
+			.map(|mode| match mode.as_str() {
+				"truncate" => LogMode::Truncate,
+				"rotate" => LogMode::Rotate,
+				_ => LogMode::Append,
+			})
+			.or_else(|| config_log.and_then(|log| log.mode))
+			.unwrap_or_default(),
+	};
 
-	let transcode_matches = config_file
+	let mut walk = config_file
 		.as_ref()
-		.map(|config_file| {
-			config_file
-				.matches
-				.iter()
-				.map(|m| {
-					let glob = m.glob.iter().map(|glob| {
-						let glob = GlobBuilder::new(glob)
-							.case_insensitive(true)
-							.build()
-							.context("Failed building glob")?;
-						let regex = Regex::new(glob.regex()).context("Failed compiling regex")?;
-						Ok(regex)
-					});
-
-					let regex = m.regex.iter().map(|regex| {
-						let regex = RegexBuilder::new(regex)
-							.case_insensitive(true)
-							.build()
-							.context("Failed compiling regex")?;
-						Ok(regex)
-					});
-
-					let extensions = m.extensions.iter().map(|ext| {
-						let mut ext = regex::escape(ext);
-						ext.insert_str(0, &"\\.");
-						ext.push_str("$");
-
-						let regex = RegexBuilder::new(&ext)
-							.case_insensitive(true)
-							.build()
-							.context("Failed compiling regex")?;
-						Ok(regex)
-					});
-
-					let mut regexes = glob
-						.chain(regex)
-						.chain(extensions)
-						.collect::<Result<Vec<_>>>()?;
-
-					if regexes.is_empty() {
-						regexes.push(default_regex.clone());
-					}
-
-					Ok(TranscodeMatch {
-						regexes,
-						to: m.to.clone(),
-					})
-				})
-				.collect::<Result<Vec<_>>>()
-		})
+		.map(|c| c.walk.clone())
+		.unwrap_or_default();
+	walk.follow_symlinks |= arg_matches.get_flag("follow-symlinks");
+	walk.skip_hidden |= arg_matches.get_flag("skip-hidden");
+	walk.same_file_system |= arg_matches.get_flag("same-file-system");
+
+	let matches = config_file
+		.as_ref()
+		.map(|config_file| transcode_matches(&config_file.matches))
 		.transpose()?
 		.filter(|matches| !matches.is_empty())
-		.unwrap_or_else(|| {
-			vec![TranscodeMatch {
-				regexes: vec![default_regex],
-				to: Transcode::default(),
-			}]
-		});
+		.unwrap_or_else(|| vec![default_match()]);
+
+	let config_targets = config_file
+		.as_ref()
+		.map(|c| c.targets.as_slice())
+		.unwrap_or_default();
+	let targets = if config_targets.is_empty() {
+		vec![Target {
+			name: None,
+			to: arg_matches
+				.get_one::<PathBuf>("to")
+				.map(|p| current_dir.join(p))
+				.or_else(|| {
+					config_file
+						.as_ref()
+						.map(|c| c.to.as_ref())
+						.flatten()
+						.map(|p| config_dir.join(p))
+				})
+				.ok_or_else(|| Error::msg("\"to\" not configured"))?
+				.canonicalize()
+				.context("Could not canonicalize \"to\" path")?,
+			matches,
+			fat_safe_names: config_file.as_ref().is_some_and(|c| c.fat_safe_names),
+		}]
+	} else {
+		if arg_matches.contains_id("to") {
+			return Err(Error::msg(
+				"The \"to\" argument can't be used together with \"targets\"",
+			));
+		}
+
+		let mut targets: Vec<Target> = Vec::new();
+		for target in config_targets {
+			if targets
+				.iter()
+				.any(|t| t.name.as_deref() == Some(target.name.as_str()))
+			{
+				return Err(Error::msg(format!(
+					"Target \"{}\" is configured more than once",
+					target.name
+				)));
+			}
+
+			targets.push(Target {
+				name: Some(target.name.clone()),
+				to: config_dir
+					.join(&target.to)
+					.canonicalize()
+					.with_context(|| {
+						format!(
+							"Could not canonicalize \"to\" path of target {}",
+							target.name
+						)
+					})?,
+				matches: if target.matches.is_empty() {
+					matches.clone()
+				} else {
+					transcode_matches(&target.matches)?
+				},
+				fat_safe_names: target.fat_safe_names,
+			});
+		}
+		targets
+	};
+
+	let exclude = config_file
+		.as_ref()
+		.map(|config_file| exclude_regexes(&config_file.exclude))
+		.transpose()?
+		.unwrap_or_default();
 
 	Ok(Config {
 		from: {
@@ -280,20 +961,8 @@ pub fn config() -> Result<Config> {
 				.canonicalize()
 				.context("Could not canonicalize \"from\" path")?
 		},
-		to: arg_matches
-			.get_one::<PathBuf>("to")
-			.map(|p| current_dir.join(p))
-			.or_else(|| {
-				config_file
-					.as_ref()
-					.map(|c| c.to.as_ref())
-					.flatten()
-					.map(|p| config_dir.join(p))
-			})
-			.ok_or_else(|| Error::msg("\"to\" not configured"))?
-			.canonicalize()
-			.context("Could not canonicalize \"to\" path")?,
-		matches: transcode_matches,
+		targets,
+		exclude,
 		jobs: arg_matches
 			.get_one("jobs")
 			.copied()
@@ -307,9 +976,218 @@ pub fn config() -> Result<Config> {
 		// 	})
 		// })
 		// .transpose()?,
+		retries: arg_matches
+			.get_one("retries")
+			.copied()
+			.or_else(|| config_file.as_ref().and_then(|c| c.retries))
+			.unwrap_or(0),
+		max_errors: if arg_matches.get_flag("fail-fast") {
+			Some(1)
+		} else {
+			arg_matches.get_one("max-errors").copied().or_else(|| {
+				config_file.as_ref().and_then(|c| match c.fail_fast {
+					true => Some(1),
+					false => c.max_errors,
+				})
+			})
+		},
+		timeout: arg_matches
+			.get_one("timeout")
+			.copied()
+			.or_else(|| config_file.as_ref().and_then(|c| c.timeout))
+			.map(Duration::from_secs),
+		stall_timeout: arg_matches
+			.get_one("stall-timeout")
+			.copied()
+			.or_else(|| config_file.as_ref().and_then(|c| c.stall_timeout))
+			.filter(|secs| *secs > 0)
+			.map(Duration::from_secs),
+		watch: arg_matches.subcommand_name() == Some("watch"),
+		plan: arg_matches.subcommand_name() == Some("plan"),
+		progress,
+		events,
+		log,
+		walk,
+		playlists: config_file.as_ref().and_then(|c| c.playlists.clone()),
 	})
 }
 
+fn default_regex() -> Regex {
+	RegexBuilder::new("\\.(flac|wav)$")
+		.case_insensitive(true)
+		.build()
+		.expect("Failed compiling default match regex")
+}
+
+fn default_video_regex() -> Regex {
+	RegexBuilder::new("\\.(mkv|mp4|m4v|webm|mov|avi)$")
+		.case_insensitive(true)
+		.build()
+		.expect("Failed compiling default video match regex")
+}
+
+fn default_match() -> TranscodeMatch {
+	TranscodeMatch {
+		regexes: vec![default_regex()],
+		exclude: Vec::new(),
+		source: None,
+		tags: Vec::new(),
+		lossy_source: LossySource::default(),
+		copy_method: CopyMethod::default(),
+		skip: false,
+		split_cue: SplitCue::default(),
+		split_chapters: false,
+		stream: StreamSelection::default(),
+		video_audio: false,
+		to: Transcode::default(),
+	}
+}
+
+fn transcode_matches(matches: &[TranscodeMatchFile]) -> Result<Vec<TranscodeMatch>> {
+	matches
+		.iter()
+		.map(|m| {
+			let glob = m.glob.iter().map(|glob| glob_regex(glob));
+			let regex = m.regex.iter().map(|regex| case_insensitive_regex(regex));
+
+			let extensions = m.extensions.iter().map(|ext| {
+				let mut ext = regex::escape(ext);
+				ext.insert_str(0, &"\\.");
+				ext.push_str("$");
+
+				let regex = RegexBuilder::new(&ext)
+					.case_insensitive(true)
+					.build()
+					.context("Failed compiling regex")?;
+				Ok(regex)
+			});
+
+			let mut regexes = glob
+				.chain(regex)
+				.chain(extensions)
+				.collect::<Result<Vec<_>>>()?;
+
+			if regexes.is_empty() {
+				regexes.push(if m.video_audio {
+					default_video_regex()
+				} else {
+					default_regex()
+				});
+			}
+
+			let to = match (&m.to, m.skip) {
+				(Some(to), _) => to.clone(),
+				// never used, the files are skipped
+				(None, true) => Transcode::default(),
+				(None, false) => {
+					return Err(Error::msg(
+						"\"to\" is missing, it can only be left out with \"skip: true\"",
+					));
+				}
+			};
+
+			// copying a video wouldn't extract its audio
+			let can_copy = matches!(to, Transcode::Copy { .. })
+				|| matches!(
+					m.lossy_source,
+					LossySource::Copy | LossySource::OnlyIfTargetBitrateLower
+				);
+			if m.video_audio && can_copy && !m.skip {
+				return Err(Error::msg(
+					"\"video_audio\" can't be used with the \"copy\" codec or a \"lossy_source\" policy copying files",
+				));
+			}
+
+			Ok(TranscodeMatch {
+				regexes,
+				exclude: exclude_regexes(&m.exclude)?,
+				source: m.source.clone(),
+				tags: tag_conditions(&m.tags)?,
+				lossy_source: m.lossy_source,
+				copy_method: m.copy_method,
+				skip: m.skip,
+				split_cue: m.split_cue,
+				split_chapters: m.split_chapters,
+				stream: m.stream.clone(),
+				video_audio: m.video_audio,
+				to,
+			})
+		})
+		.collect()
+}
+
+fn glob_regex(glob: &str) -> Result<Regex> {
+	let glob = GlobBuilder::new(glob)
+		.case_insensitive(true)
+		.build()
+		.context("Failed building glob")?;
+	let regex = Regex::new(glob.regex()).context("Failed compiling regex")?;
+	Ok(regex)
+}
+
+fn case_insensitive_regex(regex: &str) -> Result<Regex> {
+	let regex = RegexBuilder::new(regex)
+		.case_insensitive(true)
+		.build()
+		.context("Failed compiling regex")?;
+	Ok(regex)
+}
+
+fn tag_conditions(
+	tags: &BTreeMap<String, TagConditionFile>,
+) -> Result<Vec<(String, TagCondition)>> {
+	tags.iter()
+		.map(|(name, condition)| {
+			let regex = condition
+				.regex
+				.as_deref()
+				.map(case_insensitive_regex)
+				.transpose()
+				.with_context(|| format!("Invalid regex for tag \"{}\"", name))?;
+			let condition = TagCondition {
+				equals: condition.equals.clone(),
+				regex,
+				exists: condition.exists,
+			};
+			Ok((name.clone(), condition))
+		})
+		.collect()
+}
+
+fn exclude_regexes(excludes: &[ExcludeFile]) -> Result<Vec<Regex>> {
+	excludes
+		.iter()
+		.flat_map(|exclude| {
+			let glob = exclude.glob.iter().map(|glob| glob_regex(glob));
+			let regex = exclude
+				.regex
+				.iter()
+				.map(|regex| case_insensitive_regex(regex));
+			glob.chain(regex)
+		})
+		.collect()
+}
+
+/// Loads the per dir config file at `path`, `None` if there is none
+pub fn load_dir_config(path: &Path) -> Result<Option<DirConfig>> {
+	let mut file = match std::fs::File::open(path) {
+		Ok(file) => file,
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+		Err(err) => return Err(Error::new(err)),
+	};
+	let config: DirConfigFile =
+		serde_yaml::from_reader(&mut file).context("Could not parse config file")?;
+	Ok(Some(DirConfig {
+		matches: transcode_matches(&config.matches)?,
+		targets: config
+			.targets
+			.iter()
+			.map(|(name, target)| Ok((name.clone(), transcode_matches(&target.matches)?)))
+			.collect::<Result<_>>()?,
+		inherit: config.inherit,
+	}))
+}
+
 fn load_config_file(path: &Path) -> Result<Option<ConfigFile>> {
 	let mut file = match std::fs::File::open(path) {
 		Ok(file) => file,
@@ -0,0 +1,172 @@
+use crate::ui;
+use futures::{
+	future::{AbortHandle, Abortable},
+	pin_mut,
+	prelude::*,
+};
+use std::{
+	cell::{Cell, RefCell},
+	collections::HashMap,
+	convert::Infallible,
+};
+use tokio::sync::{Notify, mpsc::UnboundedReceiver};
+
+/// Commands sent from the ui to the `Scheduler`
+#[derive(Debug, Clone, Copy)]
+pub enum Command {
+	/// stops scheduling new tasks, a second `Stop` aborts the running ones
+	Stop,
+	Abort,
+	TogglePause,
+	IncreaseJobs,
+	DecreaseJobs,
+	Skip {
+		id: usize,
+	},
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StopState {
+	Running,
+	Stopping,
+	Aborting,
+}
+
+/// Decides when tasks are allowed to start, can be controlled at runtime via `Command`s
+pub struct Scheduler {
+	jobs: Cell<usize>,
+	running: Cell<usize>,
+	paused: Cell<bool>,
+	stop: Cell<StopState>,
+	abort_handles: RefCell<HashMap<usize, AbortHandle>>,
+	/// notified once for every freed up slot
+	slot_notify: Notify,
+	/// notified when the state changes, wakes all waiters
+	state_notify: Notify,
+	ui_queue: ui::MsgQueue,
+}
+
+impl Scheduler {
+	pub fn new(jobs: usize, ui_queue: ui::MsgQueue) -> Scheduler {
+		let scheduler = Scheduler {
+			jobs: Cell::new(jobs.max(1)),
+			running: Cell::new(0),
+			paused: Cell::new(false),
+			stop: Cell::new(StopState::Running),
+			abort_handles: RefCell::new(HashMap::new()),
+			slot_notify: Notify::new(),
+			state_notify: Notify::new(),
+			ui_queue,
+		};
+		scheduler.push_state();
+		scheduler
+	}
+
+	/// Waits until a task is allowed to start, returns `None` if the run is stopping
+	pub async fn acquire(&self) -> Option<Slot<'_>> {
+		loop {
+			// need to be created before checking the state, otherwise we could miss a notification
+			let slot_notified = self.slot_notify.notified();
+			let state_notified = self.state_notify.notified();
+
+			if self.stop.get() != StopState::Running {
+				return None;
+			}
+
+			if !self.paused.get() && self.running.get() < self.jobs.get() {
+				self.running.set(self.running.get() + 1);
+				return Some(Slot { scheduler: self });
+			}
+
+			pin_mut!(slot_notified, state_notified);
+			future::select(slot_notified, state_notified).await;
+		}
+	}
+
+	/// Runs the task `id`, returns `None` if it got skipped
+	pub async fn run_task<F: Future>(&self, id: usize, fut: F) -> Option<F::Output> {
+		let (handle, registration) = AbortHandle::new_pair();
+		self.abort_handles.borrow_mut().insert(id, handle);
+
+		let res = Abortable::new(fut, registration).await.ok();
+
+		self.abort_handles.borrow_mut().remove(&id);
+		res
+	}
+
+	/// Resolves once no new tasks should be started anymore
+	pub async fn stopped(&self) {
+		self.wait_for(|stop| stop != StopState::Running).await
+	}
+
+	/// Resolves once the running tasks should be aborted
+	pub async fn aborted(&self) {
+		self.wait_for(|stop| stop == StopState::Aborting).await
+	}
+
+	async fn wait_for(&self, cond: impl Fn(StopState) -> bool) {
+		loop {
+			let notified = self.state_notify.notified();
+			if cond(self.stop.get()) {
+				return;
+			}
+			notified.await;
+		}
+	}
+
+	/// Processes the commands from the ui, never resolves
+	pub async fn process_commands(&self, mut commands: UnboundedReceiver<Command>) -> Infallible {
+		while let Some(command) = commands.recv().await {
+			self.process_command(command);
+		}
+
+		// the ui is gone, there will be no commands anymore
+		future::pending().await
+	}
+
+	fn process_command(&self, command: Command) {
+		match command {
+			Command::Stop => {
+				let stop = match self.stop.get() {
+					StopState::Running => StopState::Stopping,
+					_ => StopState::Aborting,
+				};
+				self.stop.set(stop);
+			}
+			Command::Abort => self.stop.set(StopState::Aborting),
+			Command::TogglePause => self.paused.set(!self.paused.get()),
+			Command::IncreaseJobs => self.jobs.set(self.jobs.get() + 1),
+			Command::DecreaseJobs => self.jobs.set(self.jobs.get().saturating_sub(1).max(1)),
+			Command::Skip { id } => {
+				if let Some(handle) = self.abort_handles.borrow().get(&id) {
+					handle.abort();
+				}
+			}
+		}
+
+		self.state_notify.notify_waiters();
+		self.push_state();
+	}
+
+	fn push_state(&self) {
+		self.ui_queue.push(ui::Msg::SchedulerState {
+			jobs: self.jobs.get(),
+			paused: self.paused.get(),
+			stopping: self.stop.get() != StopState::Running,
+		});
+	}
+}
+
+/// A running task, frees up its slot when dropped
+pub struct Slot<'a> {
+	scheduler: &'a Scheduler,
+}
+
+impl Drop for Slot<'_> {
+	fn drop(&mut self) {
+		let running = &self.scheduler.running;
+		running.set(running.get() - 1);
+		// only a single waiter can take the slot
+		self.scheduler.slot_notify.notify_one();
+	}
+}
@@ -0,0 +1,225 @@
+use crate::config::CopyMethod;
+use anyhow::{Context, Result};
+use std::{
+	io,
+	path::{Component, Path, PathBuf},
+	sync::{
+		Arc,
+		atomic::{AtomicBool, Ordering},
+	},
+};
+use tokio::{fs, task};
+
+pub async fn copy(
+	method: CopyMethod,
+	from_path: &Path,
+	to_path: &Path,
+	to_path_tmp: &Path,
+) -> Result<()> {
+	if !matches!(method, CopyMethod::Copy) {
+		// links can't be created on top of existing files, so we need to get rid of leftovers
+		// from previous runs
+		match fs::remove_file(to_path_tmp).await {
+			Ok(()) => {}
+			Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+			Err(err) => {
+				return Err(err).with_context(|| {
+					format!(
+						"Could not remove old temporary file {}",
+						to_path_tmp.display()
+					)
+				});
+			}
+		}
+	}
+
+	let res = {
+		let from_path = from_path.to_owned();
+		let to_path = to_path.to_owned();
+		let to_path_tmp = to_path_tmp.to_owned();
+		run_blocking(to_path_tmp.clone(), move || {
+			copy_blocking(method, &from_path, &to_path, &to_path_tmp)
+		})
+		.await?
+	};
+
+	res.with_context(|| {
+		format!(
+			"Could not copy file from {} to {} (method: {:?})",
+			from_path.display(),
+			to_path_tmp.display(),
+			method
+		)
+	})
+}
+
+fn copy_blocking(
+	method: CopyMethod,
+	from_path: &Path,
+	to_path: &Path,
+	to_path_tmp: &Path,
+) -> io::Result<()> {
+	match method {
+		CopyMethod::Copy => std::fs::copy(from_path, to_path_tmp).map(|_| ()),
+		CopyMethod::Hardlink => std::fs::hard_link(from_path, to_path_tmp),
+		CopyMethod::Reflink => reflink_or_copy(from_path, to_path_tmp),
+		CopyMethod::Symlink => symlink(from_path, to_path_tmp),
+		CopyMethod::SymlinkRelative => {
+			let to_dir = to_path.parent().ok_or_else(|| {
+				io::Error::new(
+					io::ErrorKind::InvalidInput,
+					format!("Could not get parent dir for {}", to_path.display()),
+				)
+			})?;
+			symlink(&relative_path(to_dir, from_path), to_path_tmp)
+		}
+	}
+}
+
+/// Runs `f` writing `path` on the blocking thread pool
+///
+/// `f` runs to its end even if the returned future is dropped, e.g. because the transcode was
+/// cancelled, and would leave `path` behind after the `TmpFile` got removed already. Whichever of
+/// the two ends last removes `path` then.
+async fn run_blocking<T: Send + 'static>(
+	path: PathBuf,
+	f: impl FnOnce() -> T + Send + 'static,
+) -> Result<T> {
+	let done = Arc::new(AtomicBool::new(false));
+	let mut guard = RemoveOnCancel {
+		path: Some(path.clone()),
+		done: done.clone(),
+	};
+
+	let res = task::spawn_blocking(move || {
+		let res = f();
+		if done.swap(true, Ordering::SeqCst) {
+			let _ = std::fs::remove_file(&path);
+		}
+		res
+	})
+	.await
+	.context("Copy task failed");
+
+	guard.path = None;
+	res
+}
+
+struct RemoveOnCancel {
+	/// `None` once the blocking task finished and its result was received
+	path: Option<PathBuf>,
+	done: Arc<AtomicBool>,
+}
+
+impl Drop for RemoveOnCancel {
+	fn drop(&mut self) {
+		if let Some(path) = &self.path
+			&& self.done.swap(true, Ordering::SeqCst)
+		{
+			let _ = std::fs::remove_file(path);
+		}
+	}
+}
+
+fn reflink_or_copy(from_path: &Path, to_path: &Path) -> io::Result<()> {
+	#[cfg(target_os = "linux")]
+	{
+		use std::os::unix::io::AsRawFd;
+
+		let src = std::fs::File::open(from_path)?;
+		let dest = std::fs::File::create(to_path)?;
+
+		// SAFETY: both file descriptors are valid for the duration of the call
+		let res = unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+		if res == 0 {
+			return Ok(());
+		}
+
+		// not supported by the filesystem or `from` and `to` are on different filesystems,
+		// falling back to a regular copy
+	}
+
+	std::fs::copy(from_path, to_path).map(|_| ())
+}
+
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+	#[cfg(unix)]
+	{
+		std::os::unix::fs::symlink(target, link)
+	}
+
+	#[cfg(windows)]
+	{
+		std::os::windows::fs::symlink_file(target, link)
+	}
+}
+
+/// Builds a path to `target` that is relative to the directory `base`, both paths need to be
+/// absolute
+fn relative_path(base: &Path, target: &Path) -> PathBuf {
+	let mut base_comps = base.components().peekable();
+	let mut target_comps = target.components().peekable();
+
+	while let (Some(base_comp), Some(target_comp)) = (base_comps.peek(), target_comps.peek()) {
+		if base_comp != target_comp {
+			break;
+		}
+		base_comps.next();
+		target_comps.next();
+	}
+
+	base_comps
+		.map(|_| Component::ParentDir)
+		.chain(target_comps)
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+
+	fn write_later(path: &Path) -> impl FnOnce() -> io::Result<()> + Send + 'static {
+		let path = path.to_owned();
+		move || {
+			std::thread::sleep(Duration::from_millis(100));
+			std::fs::write(path, b"")
+		}
+	}
+
+	#[tokio::test]
+	async fn cancelled_copy_leaves_no_file() {
+		let dir = std::env::temp_dir();
+		let finished = dir.join(format!("audio-conv-test-{}-finished", std::process::id()));
+		let cancelled = dir.join(format!("audio-conv-test-{}-cancelled", std::process::id()));
+
+		run_blocking(finished.clone(), write_later(&finished))
+			.await
+			.unwrap()
+			.unwrap();
+		assert!(finished.exists());
+
+		let copy = run_blocking(cancelled.clone(), write_later(&cancelled));
+		assert!(
+			tokio::time::timeout(Duration::from_millis(10), copy)
+				.await
+				.is_err()
+		);
+		tokio::time::sleep(Duration::from_millis(300)).await;
+		assert!(!cancelled.exists());
+
+		std::fs::remove_file(finished).unwrap();
+	}
+
+	#[test]
+	fn relative_paths() {
+		assert_eq!(
+			relative_path(Path::new("/to/a/b"), Path::new("/from/a/c.flac")),
+			Path::new("../../../from/a/c.flac")
+		);
+		assert_eq!(
+			relative_path(Path::new("/music"), Path::new("/music/a.flac")),
+			Path::new("a.flac")
+		);
+	}
+}
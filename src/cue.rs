@@ -0,0 +1,418 @@
+use crate::{
+	config::SplitCue,
+	decode_text,
+	probe::LazyProbe,
+	segment::{Segment, SegmentSource},
+};
+use anyhow::{Context, Error, Result};
+use gstreamer::{TagList, TagMergeMode, tags};
+use std::{
+	ffi::OsStr,
+	path::{Path, PathBuf},
+	time::Duration,
+};
+
+/// Frames per second of the "mm:ss:ff" timestamps
+const FRAMES_PER_SEC: u64 = 75;
+
+#[derive(Debug, Default)]
+pub struct CueSheet {
+	pub title: Option<String>,
+	pub performer: Option<String>,
+	pub genre: Option<String>,
+	pub date: Option<String>,
+	pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug)]
+pub struct CueTrack {
+	pub number: u32,
+	/// the "FILE" the track belongs to
+	pub file: Option<String>,
+	pub title: Option<String>,
+	pub performer: Option<String>,
+	/// position of "INDEX 01" in the file
+	pub start: Duration,
+}
+
+struct PendingTrack {
+	number: u32,
+	is_audio: bool,
+	title: Option<String>,
+	performer: Option<String>,
+	start: Option<Duration>,
+}
+
+impl CueSheet {
+	/// Parses the text of a CUE sheet, tracks that aren't audio tracks or don't have an
+	/// "INDEX 01" are left out
+	pub fn parse(text: &str) -> Result<CueSheet> {
+		let mut sheet = CueSheet::default();
+		let mut file = None;
+		// the track that is currently read
+		let mut track: Option<PendingTrack> = None;
+
+		let finish_track = |sheet: &mut CueSheet, track: Option<PendingTrack>, file: &Option<_>| {
+			if let Some(PendingTrack {
+				number,
+				is_audio: true,
+				title,
+				performer,
+				start: Some(start),
+			}) = track
+			{
+				sheet.tracks.push(CueTrack {
+					number,
+					file: Option::clone(file),
+					title,
+					performer,
+					start,
+				});
+			}
+		};
+
+		for (line_number, line) in text.lines().enumerate() {
+			let args = split_args(line);
+			let (command, args) = match args.split_first() {
+				Some((command, args)) => (command.to_ascii_uppercase(), args),
+				None => continue,
+			};
+			let arg = |idx: usize| {
+				args.get(idx).cloned().with_context(|| {
+					format!(
+						"Missing argument for \"{}\" in line {}",
+						command,
+						line_number + 1
+					)
+				})
+			};
+
+			match (command.as_str(), &mut track) {
+				("FILE", _) => {
+					finish_track(&mut sheet, track.take(), &file);
+					file = Some(arg(0)?);
+				}
+				("TRACK", _) => {
+					finish_track(&mut sheet, track.take(), &file);
+					let number = arg(0)?.parse().with_context(|| {
+						format!("Invalid track number in line {}", line_number + 1)
+					})?;
+					track = Some(PendingTrack {
+						number,
+						is_audio: arg(1)?.eq_ignore_ascii_case("AUDIO"),
+						title: None,
+						performer: None,
+						start: None,
+					});
+				}
+				("TITLE", Some(track)) => track.title = Some(arg(0)?),
+				("TITLE", None) => sheet.title = Some(arg(0)?),
+				("PERFORMER", Some(track)) => track.performer = Some(arg(0)?),
+				("PERFORMER", None) => sheet.performer = Some(arg(0)?),
+				("INDEX", Some(track)) if arg(0)? == "01" => {
+					let time = arg(1)?;
+					track.start = Some(parse_time(&time).with_context(|| {
+						format!("Invalid time \"{}\" in line {}", time, line_number + 1)
+					})?);
+				}
+				("REM", None) => match args.first().map(|s| s.to_ascii_uppercase()).as_deref() {
+					Some("GENRE") => sheet.genre = args.get(1).cloned(),
+					Some("DATE") => sheet.date = args.get(1).cloned(),
+					_ => {}
+				},
+				_ => {}
+			}
+		}
+		finish_track(&mut sheet, track.take(), &file);
+
+		Ok(sheet)
+	}
+
+	/// One segment per track of `file_name`, a sheet with a single "FILE" applies to any file, as
+	/// rippers tend to reference the uncompressed file
+	pub fn segments(&self, file_name: &OsStr) -> Vec<Segment> {
+		let is_single_file = self
+			.tracks
+			.windows(2)
+			.all(|tracks| tracks[0].file == tracks[1].file);
+		let file_stem = Path::new(file_name).file_stem();
+		let tracks: Vec<_> = self
+			.tracks
+			.iter()
+			.filter(|track| {
+				is_single_file
+					|| track.file.as_ref().is_some_and(|file| {
+						let stem = Path::new(file.as_str()).file_stem();
+						stem.zip(file_stem).is_some_and(|(stem, file_stem)| {
+							stem.to_string_lossy()
+								.eq_ignore_ascii_case(&file_stem.to_string_lossy())
+						})
+					})
+			})
+			.collect();
+
+		tracks
+			.iter()
+			.enumerate()
+			.map(|(idx, track)| {
+				let mut tag_list = TagList::new();
+				{
+					let tag_list = tag_list.make_mut();
+					let performer = track.performer.as_ref().or(self.performer.as_ref());
+					if let Some(title) = &track.title {
+						tag_list.add::<tags::Title>(&title.as_str(), TagMergeMode::Replace);
+					}
+					if let Some(performer) = performer {
+						tag_list.add::<tags::Artist>(&performer.as_str(), TagMergeMode::Replace);
+					}
+					if let Some(album) = &self.title {
+						tag_list.add::<tags::Album>(&album.as_str(), TagMergeMode::Replace);
+					}
+					if let Some(album_artist) = &self.performer {
+						tag_list.add::<tags::AlbumArtist>(
+							&album_artist.as_str(),
+							TagMergeMode::Replace,
+						);
+					}
+					if let Some(genre) = &self.genre {
+						tag_list.add::<tags::Genre>(&genre.as_str(), TagMergeMode::Replace);
+					}
+					let year = self
+						.date
+						.as_ref()
+						.and_then(|date| date.get(..4)?.parse().ok());
+					if let Some(date) = year.and_then(|year| gstreamer::DateTime::from_y(year).ok())
+					{
+						tag_list.add::<tags::DateTime>(&date, TagMergeMode::Replace);
+					}
+					tag_list.add::<tags::TrackNumber>(&track.number, TagMergeMode::Replace);
+					tag_list.add::<tags::TrackCount>(&(tracks.len() as u32), TagMergeMode::Replace);
+				}
+
+				Segment {
+					source: SegmentSource::Cue,
+					number: track.number,
+					title: track.title.clone(),
+					start: track.start,
+					end: tracks.get(idx + 1).map(|next| next.start),
+					tags: tag_list,
+				}
+			})
+			.collect()
+	}
+}
+
+/// The CUE sheet of the audio file of `probe`, read from "<name>.cue" or "<name>.<ext>.cue" next
+/// to it, or the "CUESHEET" tag of the file, which is only probed for the latter
+pub fn find(probe: &mut LazyProbe, split_cue: SplitCue) -> Result<Option<CueSheet>> {
+	if split_cue == SplitCue::Off {
+		return Ok(None);
+	}
+
+	let path = probe.path();
+
+	let mut with_cue_ext = path.as_os_str().to_owned();
+	with_cue_ext.push(".cue");
+	for cue_path in [path.with_extension("cue"), PathBuf::from(with_cue_ext)] {
+		let bytes = match std::fs::read(&cue_path) {
+			Ok(bytes) => bytes,
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+			Err(err) => {
+				return Err(Error::new(err))
+					.with_context(|| format!("Unable to read {}", cue_path.display()));
+			}
+		};
+		let sheet = CueSheet::parse(&decode_text(&bytes))
+			.with_context(|| format!("Unable to parse {}", cue_path.display()))?;
+		return Ok(Some(sheet).filter(|sheet| !sheet.tracks.is_empty()));
+	}
+
+	if split_cue == SplitCue::Embedded
+		&& let Some(text) = probe.get()?.tag_values("CUESHEET").first()
+	{
+		let sheet = CueSheet::parse(text).context("Unable to parse the CUESHEET tag")?;
+		return Ok(Some(sheet).filter(|sheet| !sheet.tracks.is_empty()));
+	}
+
+	Ok(None)
+}
+
+/// Splits a line into its whitespace separated arguments, arguments can be quoted
+fn split_args(line: &str) -> Vec<String> {
+	let mut args = Vec::new();
+	let mut chars = line.trim().chars().peekable();
+
+	while let Some(c) = chars.next() {
+		match c {
+			c if c.is_whitespace() => {}
+			'"' => {
+				let arg: String = chars.by_ref().take_while(|c| *c != '"').collect();
+				args.push(arg);
+			}
+			c => {
+				let mut arg = String::from(c);
+				while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+					arg.push(c);
+				}
+				args.push(arg);
+			}
+		}
+	}
+
+	args
+}
+
+/// Parses "mm:ss:ff"
+fn parse_time(time: &str) -> Option<Duration> {
+	let mut parts = time.split(':').map(|part| part.parse::<u64>().ok());
+	let (minutes, secs, frames) = (parts.next()??, parts.next()??, parts.next()??);
+	if parts.next().is_some() || secs >= 60 || frames >= FRAMES_PER_SEC {
+		return None;
+	}
+
+	let nanos = frames * 1_000_000_000 / FRAMES_PER_SEC;
+	Some(Duration::from_secs(minutes * 60 + secs) + Duration::from_nanos(nanos))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn split_args_quoted() {
+		assert_eq!(
+			split_args(r#"  TITLE "Hello  World" extra"#),
+			["TITLE", "Hello  World", "extra"]
+		);
+		assert_eq!(
+			split_args(r#"FILE "a b.wav" WAVE"#),
+			["FILE", "a b.wav", "WAVE"]
+		);
+		assert_eq!(split_args(r#"TITLE """#), ["TITLE", ""]);
+		// an unterminated quote ends with the line
+		assert_eq!(split_args(r#"TITLE "open"#), ["TITLE", "open"]);
+		assert!(split_args("   ").is_empty());
+	}
+
+	#[test]
+	fn parse_time_frames() {
+		assert_eq!(parse_time("00:00:00"), Some(Duration::ZERO));
+		assert_eq!(
+			parse_time("01:02:15"),
+			Some(Duration::from_secs(62) + Duration::from_millis(200))
+		);
+		assert_eq!(
+			parse_time("120:00:74"),
+			Some(Duration::from_secs(7200) + Duration::from_nanos(74 * 1_000_000_000 / 75))
+		);
+		assert_eq!(parse_time("00:00:75"), None);
+		assert_eq!(parse_time("00:60:00"), None);
+		assert_eq!(parse_time("00:00"), None);
+		assert_eq!(parse_time("00:00:00:00"), None);
+		assert_eq!(parse_time("aa:00:00"), None);
+	}
+
+	#[test]
+	fn parse_sheet() {
+		let sheet = CueSheet::parse(
+			"REM GENRE Rock\r\n\
+			 REM DATE 1999\r\n\
+			 PERFORMER \"The Band\"\r\n\
+			 TITLE \"The Album\"\r\n\
+			 FILE \"album.wav\" WAVE\r\n\
+			 \x20 TRACK 01 AUDIO\r\n\
+			 \x20   TITLE \"One\"\r\n\
+			 \x20   INDEX 00 00:00:00\r\n\
+			 \x20   INDEX 01 00:00:32\r\n\
+			 \x20 TRACK 02 AUDIO\r\n\
+			 \x20   TITLE \"Two\"\r\n\
+			 \x20   PERFORMER \"Guest\"\r\n\
+			 \x20   INDEX 01 03:20:00\r\n",
+		)
+		.unwrap();
+
+		assert_eq!(sheet.title.as_deref(), Some("The Album"));
+		assert_eq!(sheet.performer.as_deref(), Some("The Band"));
+		assert_eq!(sheet.genre.as_deref(), Some("Rock"));
+		assert_eq!(sheet.date.as_deref(), Some("1999"));
+		assert_eq!(sheet.tracks.len(), 2);
+
+		let one = &sheet.tracks[0];
+		assert_eq!(one.number, 1);
+		assert_eq!(one.file.as_deref(), Some("album.wav"));
+		assert_eq!(one.title.as_deref(), Some("One"));
+		assert_eq!(one.performer, None);
+		// "INDEX 01" is the start, not the pregap of "INDEX 00"
+		assert_eq!(one.start, parse_time("00:00:32").unwrap());
+
+		let two = &sheet.tracks[1];
+		assert_eq!(two.performer.as_deref(), Some("Guest"));
+		assert_eq!(two.start, Duration::from_secs(200));
+	}
+
+	#[test]
+	fn parse_multiple_files() {
+		let sheet = CueSheet::parse(
+			"FILE \"cd1.flac\" WAVE\n\
+			 TRACK 01 AUDIO\n\
+			 INDEX 01 00:00:00\n\
+			 TRACK 02 AUDIO\n\
+			 INDEX 01 04:00:00\n\
+			 FILE \"cd2.flac\" WAVE\n\
+			 TRACK 03 AUDIO\n\
+			 INDEX 01 00:00:00\n",
+		)
+		.unwrap();
+
+		let files: Vec<_> = sheet
+			.tracks
+			.iter()
+			.map(|track| (track.number, track.file.as_deref()))
+			.collect();
+		assert_eq!(
+			files,
+			[
+				(1, Some("cd1.flac")),
+				(2, Some("cd1.flac")),
+				(3, Some("cd2.flac"))
+			]
+		);
+	}
+
+	#[test]
+	fn parse_skips_data_tracks_and_tracks_without_index() {
+		let sheet = CueSheet::parse(
+			"FILE \"a.bin\" BINARY\n\
+			 TRACK 01 MODE1/2352\n\
+			 INDEX 01 00:00:00\n\
+			 TRACK 02 AUDIO\n\
+			 INDEX 00 01:00:00\n\
+			 TRACK 03 audio\n\
+			 INDEX 01 02:00:00\n",
+		)
+		.unwrap();
+
+		assert_eq!(sheet.tracks.len(), 1);
+		assert_eq!(sheet.tracks[0].number, 3);
+	}
+
+	#[test]
+	fn parse_errors() {
+		assert!(CueSheet::parse("TRACK xx AUDIO").is_err());
+		assert!(CueSheet::parse("TRACK 01").is_err());
+		assert!(CueSheet::parse("TRACK 01 AUDIO\nINDEX 01 00:00:75").is_err());
+	}
+
+	#[test]
+	fn parse_latin1() {
+		let bytes = b"TITLE \"Caf\xE9\"\nTRACK 01 AUDIO\nTITLE \"\xDCber\"\nINDEX 01 00:00:00\n";
+		let sheet = CueSheet::parse(&decode_text(bytes)).unwrap();
+		assert_eq!(sheet.title.as_deref(), Some("Café"));
+		assert_eq!(sheet.tracks[0].title.as_deref(), Some("Über"));
+
+		// UTF-8 with a byte order mark
+		let bytes = "\u{FEFF}TITLE \"Café\"\n".as_bytes();
+		let sheet = CueSheet::parse(&decode_text(bytes)).unwrap();
+		assert_eq!(sheet.title.as_deref(), Some("Café"));
+	}
+}
@@ -0,0 +1,428 @@
+use crate::{
+	config::{
+		self, Config, DIR_CONFIG_FILE_NAME, DirConfig, LossySource, SplitCue, StreamSelection,
+		Target, Transcode, TranscodeMatch,
+	},
+	path_to_bytes,
+	probe::LazyProbe,
+};
+use anyhow::{Context, Result};
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+	rc::Rc,
+};
+
+/// Lazily loaded `.audio-conv.yaml` files of the sub dirs of "from", cached per dir
+#[derive(Debug, Default)]
+pub struct DirConfigs {
+	/// the closest dir config of every dir looked at so far
+	dirs: HashMap<PathBuf, Option<Rc<Layer>>>,
+}
+
+/// How a file gets converted for a single target, see `DirConfigs::transcodes`
+#[derive(Clone, Debug)]
+pub struct TargetTranscode {
+	pub transcode: Transcode,
+	/// the dir config the match comes from, `None` for the main config
+	pub rule: Option<PathBuf>,
+	pub split_cue: SplitCue,
+	pub split_chapters: bool,
+	pub stream: StreamSelection,
+	pub video_audio: bool,
+}
+
+impl TargetTranscode {
+	/// Whether the file might get split into multiple outputs
+	pub fn is_split(&self) -> bool {
+		self.split_cue != SplitCue::Off || self.split_chapters
+	}
+}
+
+#[derive(Debug)]
+struct Layer {
+	path: PathBuf,
+	config: DirConfig,
+	parent: Option<Rc<Layer>>,
+}
+
+impl Layer {
+	/// The matches of the dir config for `target`
+	fn matches(&self, target: &Target) -> &[TranscodeMatch] {
+		target
+			.name
+			.as_ref()
+			.and_then(|name| self.config.targets.get(name))
+			.unwrap_or(&self.config.matches)
+	}
+}
+
+impl DirConfigs {
+	/// Loads the config of `dir` and its parent dirs.
+	///
+	/// An error is only returned the first time, afterwards the config of the parent dir is used.
+	pub fn load(&mut self, root: &Path, dir: &Path) -> Result<()> {
+		self.layer(root, dir).map(|_| ())
+	}
+
+	/// The transcode of the first match for `path` for every target of the config, together with
+	/// the path of the dir config it comes from, `None` if it comes from the main config.
+	///
+	/// The file is only probed if a match has conditions on its audio stream or tags, or a
+	/// `lossy_source` policy, and at most once for all targets. `None` for the targets no match
+	/// applies to or that skip the file.
+	pub fn transcodes(
+		&mut self,
+		config: &Config,
+		probe: &mut LazyProbe,
+	) -> Result<Vec<Option<TargetTranscode>>> {
+		let path = probe.path();
+		let mut probe = Some(probe);
+		config
+			.targets
+			.iter()
+			.map(|target| self.find(config, target, path, &mut probe))
+			.collect()
+	}
+
+	/// Like `transcodes`, but ignores the conditions on the audio stream and tags, and the
+	/// `lossy_source` policies, for files that don't exist anymore
+	pub fn transcodes_by_path(
+		&mut self,
+		config: &Config,
+		path: &Path,
+	) -> Result<Vec<Option<TargetTranscode>>> {
+		config
+			.targets
+			.iter()
+			.map(|target| self.find(config, target, path, &mut None))
+			.collect()
+	}
+
+	/// The transcodes `path` might get for every target, depending on its audio stream and tags:
+	/// the ones of the matches with conditions up to the first match without any. `None` for
+	/// matches that skip the file.
+	///
+	/// Used to find the outputs of a file without probing it, so a `lossy_source` policy adds a
+	/// copy, or `None` if it skips the file.
+	pub fn candidates(
+		&mut self,
+		config: &Config,
+		path: &Path,
+	) -> Result<Vec<Vec<Option<TargetTranscode>>>> {
+		let path_bytes = path_to_bytes(path);
+		let (layers, inherit) = self.layers(config, path)?;
+
+		config
+			.targets
+			.iter()
+			.map(|target| {
+				let rules = layers
+					.iter()
+					.map(|layer| (layer.matches(target), Some(&layer.path)))
+					.chain(inherit.then_some((target.matches.as_slice(), None)));
+
+				let mut candidates = Vec::new();
+				for (matches, rule) in rules {
+					for m in matches.iter().filter(|m| is_path_match(m, &path_bytes)) {
+						let candidate = target_transcode(m, &mut None, rule.cloned())?;
+						if let Some(candidate) = &candidate
+							&& candidate.transcode.bitrate().is_some()
+						{
+							match m.lossy_source {
+								LossySource::Transcode => {}
+								LossySource::Copy | LossySource::OnlyIfTargetBitrateLower => {
+									candidates.push(Some(TargetTranscode {
+										transcode: Transcode::Copy {
+											method: m.copy_method,
+										},
+										..candidate.clone()
+									}));
+								}
+								LossySource::Skip => candidates.push(None),
+							}
+						}
+						candidates.push(candidate);
+
+						if m.source.is_none() && m.tags.is_empty() {
+							return Ok(candidates);
+						}
+					}
+				}
+				Ok(candidates)
+			})
+			.collect()
+	}
+
+	fn find(
+		&mut self,
+		config: &Config,
+		target: &Target,
+		path: &Path,
+		probe: &mut Option<&mut LazyProbe>,
+	) -> Result<Option<TargetTranscode>> {
+		let path_bytes = path_to_bytes(path);
+		let (layers, inherit) = self.layers(config, path)?;
+
+		for layer in &layers {
+			if let Some(m) = find_match(layer.matches(target), &path_bytes, probe)? {
+				return target_transcode(m, probe, Some(layer.path.clone()));
+			}
+		}
+
+		if !inherit {
+			return Ok(None);
+		}
+		match find_match(&target.matches, &path_bytes, probe)? {
+			Some(m) => target_transcode(m, probe, None),
+			None => Ok(None),
+		}
+	}
+
+	/// The dir configs applying to `path`, the closest one first, and whether the matches of the
+	/// main config apply after theirs
+	fn layers(&mut self, config: &Config, path: &Path) -> Result<(Vec<Rc<Layer>>, bool)> {
+		let mut layers = Vec::new();
+		let mut layer = match path.parent() {
+			Some(dir) => self.layer(&config.from, dir)?,
+			None => None,
+		};
+		while let Some(current) = layer {
+			layers.push(current.clone());
+			if !current.config.inherit {
+				return Ok((layers, false));
+			}
+			layer = current.parent.clone();
+		}
+		Ok((layers, true))
+	}
+
+	fn layer(&mut self, root: &Path, dir: &Path) -> Result<Option<Rc<Layer>>> {
+		if let Some(layer) = self.dirs.get(dir) {
+			return Ok(layer.clone());
+		}
+
+		let parent = match dir.parent() {
+			Some(parent) if dir != root && parent.starts_with(root) => self.layer(root, parent)?,
+			_ => None,
+		};
+
+		// used if loading the config fails
+		self.dirs.insert(dir.to_owned(), parent.clone());
+
+		let path = dir.join(DIR_CONFIG_FILE_NAME);
+		let dir_config = config::load_dir_config(&path)
+			.with_context(|| format!("Failed loading config file {}", path.display()))?;
+
+		let layer = match dir_config {
+			Some(dir_config) => Some(Rc::new(Layer {
+				path,
+				config: dir_config,
+				parent,
+			})),
+			None => parent,
+		};
+		self.dirs.insert(dir.to_owned(), layer.clone());
+		Ok(layer)
+	}
+}
+
+fn find_match<'a>(
+	matches: &'a [TranscodeMatch],
+	path_bytes: &[u8],
+	probe: &mut Option<&mut LazyProbe>,
+) -> Result<Option<&'a TranscodeMatch>> {
+	for m in matches {
+		if !is_path_match(m, path_bytes) {
+			continue;
+		}
+
+		let satisfied = match probe.as_mut() {
+			Some(probe) if m.source.is_some() || !m.tags.is_empty() => {
+				let probe = probe.get()?;
+				m.source
+					.as_ref()
+					.is_none_or(|source| probe.satisfies(source))
+					&& m.tags
+						.iter()
+						.all(|(name, condition)| probe.satisfies_tag(name, condition))
+			}
+			_ => true,
+		};
+		if satisfied {
+			return Ok(Some(m));
+		}
+	}
+
+	Ok(None)
+}
+
+/// Whether the path of a file matches `m`, regardless of its conditions on the audio stream and tags
+fn is_path_match(m: &TranscodeMatch, path_bytes: &[u8]) -> bool {
+	m.regexes.iter().any(|regex| regex.is_match(path_bytes))
+		&& !m.exclude.iter().any(|regex| regex.is_match(path_bytes))
+}
+
+/// `None` if the file is skipped by `m`
+fn target_transcode(
+	m: &TranscodeMatch,
+	probe: &mut Option<&mut LazyProbe>,
+	rule: Option<PathBuf>,
+) -> Result<Option<TargetTranscode>> {
+	if m.skip {
+		return Ok(None);
+	}
+
+	let transcode = lossy_source_transcode(m, probe)?.map(|transcode| TargetTranscode {
+		transcode,
+		rule,
+		split_cue: m.split_cue,
+		split_chapters: m.split_chapters,
+		stream: m.stream.clone(),
+		video_audio: m.video_audio,
+	});
+	Ok(transcode)
+}
+
+/// Applies the `lossy_source` policy of `m`, `None` if the file is skipped
+fn lossy_source_transcode(
+	m: &TranscodeMatch,
+	probe: &mut Option<&mut LazyProbe>,
+) -> Result<Option<Transcode>> {
+	let (target_bitrate, probe) = match (m.to.bitrate(), probe.as_mut()) {
+		(Some(bitrate), Some(probe)) if m.lossy_source != LossySource::Transcode => {
+			(bitrate, probe)
+		}
+		_ => return Ok(Some(m.to.clone())),
+	};
+
+	let probe = probe.get()?;
+	if probe.lossless {
+		return Ok(Some(m.to.clone()));
+	}
+
+	let copy = Transcode::Copy {
+		method: m.copy_method,
+	};
+	let to = match m.lossy_source {
+		LossySource::Transcode => Some(m.to.clone()),
+		LossySource::Copy => Some(copy),
+		LossySource::Skip => None,
+		// if the bitrate of the file is unknown it's kept as it is
+		LossySource::OnlyIfTargetBitrateLower => match probe.bitrate {
+			Some(bitrate) if u64::from(target_bitrate) * 1000 < bitrate => Some(m.to.clone()),
+			_ => Some(copy),
+		},
+	};
+	Ok(to)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::{LogConfig, LogFormat, LogMode, WalkConfig};
+
+	fn write(path: &Path, yaml: &str) {
+		std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+		std::fs::write(path, yaml).unwrap();
+	}
+
+	fn matches(dir: &Path, yaml: &str) -> Vec<TranscodeMatch> {
+		let path = dir.join("main.yaml");
+		write(&path, yaml);
+		config::load_dir_config(&path).unwrap().unwrap().matches
+	}
+
+	fn config(dir: &Path) -> Config {
+		let target = |name: Option<&str>, yaml| Target {
+			name: name.map(String::from),
+			to: dir.join("to"),
+			matches: matches(dir, yaml),
+			fat_safe_names: false,
+		};
+		Config {
+			from: dir.join("from"),
+			targets: vec![
+				target(
+					None,
+					"matches: [{ extensions: [flac, wav], to: { codec: opus } }]",
+				),
+				target(
+					Some("car"),
+					"matches: [{ extensions: [flac, wav], to: { codec: mp3 } }]",
+				),
+			],
+			exclude: Vec::new(),
+			jobs: None,
+			retries: 0,
+			max_errors: None,
+			timeout: None,
+			stall_timeout: None,
+			watch: false,
+			plan: false,
+			progress: None,
+			events: None,
+			log: LogConfig {
+				path: dir.join("audio-conv.log"),
+				format: LogFormat::Text,
+				mode: LogMode::Append,
+			},
+			walk: WalkConfig::default(),
+			playlists: None,
+		}
+	}
+
+	#[test]
+	fn layered_dir_configs() {
+		let dir = std::env::temp_dir().join(format!("audio-conv-test-{}", std::process::id()));
+		let from = dir.join("from");
+		write(
+			&from.join("a").join(DIR_CONFIG_FILE_NAME),
+			"matches: [{ extensions: [flac], to: { codec: flac } }]\n\
+			 targets: { car: { matches: [{ extensions: [flac], skip: true }] } }",
+		);
+		write(
+			&from.join("a/b").join(DIR_CONFIG_FILE_NAME),
+			"matches: [{ extensions: [wav], to: { codec: flac } }]\ninherit: false",
+		);
+		let config = config(&dir);
+		let mut dir_configs = DirConfigs::default();
+
+		// the extension and the dir config of the transcode by target
+		let mut transcodes = |path: &str| -> Vec<Option<(&str, Option<PathBuf>)>> {
+			dir_configs
+				.transcodes_by_path(&config, &from.join(path))
+				.unwrap()
+				.into_iter()
+				.map(|transcode| {
+					transcode.map(|transcode| {
+						let rule = transcode
+							.rule
+							.map(|rule| rule.strip_prefix(&from).unwrap().to_path_buf());
+						(transcode.transcode.extension().unwrap(), rule)
+					})
+				})
+				.collect()
+		};
+		let rule = |dir: &str| Some(Path::new(dir).join(DIR_CONFIG_FILE_NAME));
+
+		assert_eq!(
+			transcodes("x.flac"),
+			[Some(("opus", None)), Some(("mp3", None))]
+		);
+		assert_eq!(transcodes("a/x.flac"), [Some(("flac", rule("a"))), None]);
+		// no match of the dir config, so the main config applies
+		assert_eq!(
+			transcodes("a/x.wav"),
+			[Some(("opus", None)), Some(("mp3", None))]
+		);
+		// neither the main config nor the one of "a" apply without "inherit"
+		assert_eq!(transcodes("a/b/x.flac"), [None, None]);
+		assert_eq!(
+			transcodes("a/b/c/x.wav"),
+			[Some(("flac", rule("a/b"))), Some(("flac", rule("a/b")))]
+		);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,182 @@
+use crate::{
+	GErrorMessage,
+	config::{LogConfig, LogFormat, LogMode, Transcode},
+};
+use anyhow::{Context, Error, Result};
+use serde::Serialize;
+use std::{
+	borrow::Cow,
+	ffi::OsString,
+	fmt::Write as FmtWrite,
+	io,
+	path::{Path, PathBuf},
+	time::SystemTime,
+};
+use tokio::{fs, io::AsyncWriteExt};
+
+/// Number of logs of previous runs kept in the `rotate` mode
+const ROTATED_LOGS: usize = 5;
+
+pub struct ErrorLog {
+	path: PathBuf,
+	format: LogFormat,
+}
+
+/// What was being done when the error occurred
+#[derive(Debug, Clone, Copy)]
+pub struct LogContext<'a> {
+	pub action: &'static str,
+	pub from: Option<&'a Path>,
+	pub to: Option<&'a Path>,
+	pub transcode: Option<&'a Transcode>,
+}
+
+/// A line of the log in the JSON format
+#[derive(Debug, Serialize)]
+struct Entry<'a> {
+	timestamp: String,
+	action: &'static str,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	from: Option<Cow<'a, str>>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	to: Option<Cow<'a, str>>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	transcode: Option<&'a Transcode>,
+	/// the error message followed by its causes
+	errors: Vec<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	gstreamer: Option<GstreamerError<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct GstreamerError<'a> {
+	/// path of the element that posted the error
+	element: &'a str,
+	domain: &'a str,
+	message: &'a str,
+	debug: Option<&'a str>,
+}
+
+impl ErrorLog {
+	/// Prepares the log file for a new run according to the configured mode
+	pub fn open(config: &LogConfig) -> Result<ErrorLog> {
+		match config.mode {
+			LogMode::Append => {}
+			LogMode::Truncate => remove_if_exists(&config.path).with_context(|| {
+				format!("Unable to truncate log file {}", config.path.display())
+			})?,
+			LogMode::Rotate => rotate(&config.path)
+				.with_context(|| format!("Unable to rotate log file {}", config.path.display()))?,
+		}
+
+		Ok(ErrorLog {
+			path: config.path.clone(),
+			format: config.format,
+		})
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	/// Appends `err` to the log file, only returns an error if logging itself failed
+	pub async fn log(&self, context: LogContext<'_>, err: Error) -> Result<()> {
+		let mut log_file = match fs::OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&self.path)
+			.await
+		{
+			Ok(log_file) => log_file,
+			Err(fs_err) => {
+				let err = err.context(fs_err).context("Unable to open log file");
+				return Err(err);
+			}
+		};
+
+		let err_str = match self.format(context, &err) {
+			Ok(err_str) => err_str,
+			Err(format_err) => {
+				let err = err.context(format!(
+					"Unable to format transcoding error for logging (error: {})",
+					format_err
+				));
+				return Err(err);
+			}
+		};
+
+		log_file
+			.write_all(err_str.as_ref())
+			.await
+			.map_err(|fs_err| {
+				err.context(format!(
+					"Unable to write transcoding error to log file (fs error: {})",
+					fs_err
+				))
+			})
+	}
+
+	fn format(&self, context: LogContext<'_>, err: &Error) -> Result<String> {
+		let timestamp = humantime::format_rfc3339_millis(SystemTime::now()).to_string();
+
+		let mut err_str = String::new();
+		match self.format {
+			LogFormat::Text => {
+				writeln!(&mut err_str, "[{}] {:?}", timestamp, err)?;
+			}
+			LogFormat::Json => {
+				let gstreamer = err
+					.chain()
+					.find_map(|e| e.downcast_ref::<GErrorMessage>())
+					.map(|msg| GstreamerError {
+						element: &msg.src,
+						domain: msg.source.domain().as_str(),
+						message: &msg.error,
+						debug: msg.debug.as_deref(),
+					});
+
+				let entry = Entry {
+					timestamp,
+					action: context.action,
+					from: context.from.map(Path::to_string_lossy),
+					to: context.to.map(Path::to_string_lossy),
+					transcode: context.transcode,
+					errors: err.chain().map(|e| e.to_string()).collect(),
+					gstreamer,
+				};
+				err_str = serde_json::to_string(&entry)?;
+				err_str.push('\n');
+			}
+		}
+
+		Ok(err_str)
+	}
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+	match std::fs::remove_file(path) {
+		Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+		_ => Ok(()),
+	}
+}
+
+fn rotate(path: &Path) -> io::Result<()> {
+	if !path.exists() {
+		return Ok(());
+	}
+
+	let rotated_path = |n: usize| {
+		let mut path = OsString::from(path);
+		path.push(format!(".{}", n));
+		PathBuf::from(path)
+	};
+
+	remove_if_exists(&rotated_path(ROTATED_LOGS))?;
+	for n in (1..ROTATED_LOGS).rev() {
+		match std::fs::rename(rotated_path(n), rotated_path(n + 1)) {
+			Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+			_ => {}
+		}
+	}
+	std::fs::rename(path, rotated_path(1))
+}
@@ -0,0 +1,205 @@
+use crate::{
+	config::{Config, EventsOutput, Target},
+	get_output_path, ui,
+};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{
+	borrow::Cow,
+	io::{self, Write},
+	path::{Path, PathBuf},
+};
+
+/// Version of the event schema, gets increased on incompatible changes
+const SCHEMA_VERSION: u32 = 2;
+
+/// Events as they are written to the event stream, one JSON object per line
+///
+/// The schema is documented in the README, changes need to be reflected there.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+	Init {
+		version: u32,
+		task_len: usize,
+		total_size: u64,
+		scan_errors: usize,
+		from: Cow<'a, str>,
+		targets: Vec<EventTarget<'a>>,
+		log_path: Cow<'a, str>,
+	},
+	TasksAdded {
+		task_len: usize,
+		total_size: u64,
+	},
+	TaskStart {
+		id: usize,
+		from: Cow<'a, str>,
+		outputs: Vec<EventOutput<'a>>,
+	},
+	TaskProgress {
+		id: usize,
+		ratio: f64,
+		position_secs: f64,
+	},
+	TaskEnd {
+		id: usize,
+		output_size: u64,
+		duration_secs: f64,
+	},
+	TaskError {
+		id: usize,
+		errors: &'a [String],
+	},
+	TaskSkipped {
+		id: usize,
+	},
+	ScanError {
+		errors: &'a [String],
+	},
+	SchedulerState {
+		jobs: usize,
+		paused: bool,
+		stopping: bool,
+	},
+	Exit,
+}
+
+#[derive(Debug, Serialize)]
+struct EventTarget<'a> {
+	name: Option<&'a str>,
+	to: Cow<'a, str>,
+}
+
+#[derive(Debug, Serialize)]
+struct EventOutput<'a> {
+	target: Option<&'a str>,
+	to: Cow<'a, str>,
+	codec: &'static str,
+}
+
+pub struct EventWriter {
+	out: Box<dyn Write + Send>,
+	from: PathBuf,
+	targets: Vec<Target>,
+}
+
+impl EventWriter {
+	pub fn new(config: &Config, output: &EventsOutput) -> Result<EventWriter> {
+		let out: Box<dyn Write + Send> = match output {
+			EventsOutput::Stdout => Box::new(io::stdout()),
+			EventsOutput::File(path) => {
+				// opening a FIFO blocks until there is a reader
+				let file = std::fs::OpenOptions::new()
+					.write(true)
+					.create(true)
+					.truncate(true)
+					.open(path)
+					.with_context(|| format!("Unable to open events file {}", path.display()))?;
+				Box::new(file)
+			}
+		};
+
+		Ok(EventWriter {
+			out,
+			from: config.from.clone(),
+			targets: config.targets.clone(),
+		})
+	}
+
+	pub fn write(&mut self, msg: &ui::Msg) -> Result<()> {
+		let event = match msg {
+			ui::Msg::Init {
+				task_len,
+				total_size,
+				scan_errors,
+				log_path,
+			} => Event::Init {
+				version: SCHEMA_VERSION,
+				task_len: *task_len,
+				total_size: *total_size,
+				scan_errors: *scan_errors,
+				from: self.from.to_string_lossy(),
+				targets: self
+					.targets
+					.iter()
+					.map(|target| EventTarget {
+						name: target.name.as_deref(),
+						to: target.to.to_string_lossy(),
+					})
+					.collect(),
+				log_path: log_path.to_string_lossy(),
+			},
+			ui::Msg::TasksAdded {
+				task_len,
+				total_size,
+			} => Event::TasksAdded {
+				task_len: *task_len,
+				total_size: *total_size,
+			},
+			ui::Msg::Exit => Event::Exit,
+			ui::Msg::TaskStart { id, args } => Event::TaskStart {
+				id: *id,
+				from: path_to_string(self.from.join(&args.rel_from_path)),
+				outputs: args
+					.outputs
+					.iter()
+					.map(|output| {
+						let target = &self.targets[output.target];
+						EventOutput {
+							target: target.name.as_deref(),
+							to: path_to_string(get_output_path(
+								&self.targets,
+								&args.rel_from_path,
+								output,
+							)),
+							codec: output.transcode.codec_name(),
+						}
+					})
+					.collect(),
+			},
+			ui::Msg::TaskProgress {
+				id,
+				ratio,
+				position,
+			} => Event::TaskProgress {
+				id: *id,
+				ratio: *ratio,
+				position_secs: position.as_secs_f64(),
+			},
+			ui::Msg::TaskEnd {
+				id,
+				output_size,
+				duration,
+			} => Event::TaskEnd {
+				id: *id,
+				output_size: *output_size,
+				duration_secs: duration.as_secs_f64(),
+			},
+			ui::Msg::TaskError { id, errors } => Event::TaskError { id: *id, errors },
+			ui::Msg::ScanError { errors } => Event::ScanError { errors },
+			ui::Msg::TaskSkipped { id } => Event::TaskSkipped { id: *id },
+			ui::Msg::SchedulerState {
+				jobs,
+				paused,
+				stopping,
+			} => Event::SchedulerState {
+				jobs: *jobs,
+				paused: *paused,
+				stopping: *stopping,
+			},
+		};
+
+		serde_json::to_writer(&mut self.out, &event).context("Unable to serialize event")?;
+		self.out
+			.write_all(b"\n")
+			.and_then(|()| self.out.flush())
+			.context("Unable to write event")?;
+
+		Ok(())
+	}
+}
+
+fn path_to_string(path: impl AsRef<Path>) -> Cow<'static, str> {
+	Cow::Owned(path.as_ref().to_string_lossy().into_owned())
+}
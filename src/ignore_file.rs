@@ -0,0 +1,59 @@
+use ignore::gitignore::Gitignore;
+use std::{
+	collections::HashMap,
+	path::{Path, PathBuf},
+};
+
+/// Name of the files excluding paths in their dir and its sub dirs, using the gitignore syntax
+pub const IGNORE_FILE_NAME: &str = ".audio-conv-ignore";
+
+/// Lazily loaded ignore files, cached per dir
+#[derive(Debug, Default)]
+pub struct IgnoreFiles {
+	dirs: HashMap<PathBuf, Option<Gitignore>>,
+}
+
+impl IgnoreFiles {
+	/// Whether `path` is excluded by an ignore file in one of its parent dirs up to `root`.
+	///
+	/// Ignore files in deeper dirs take precedence, so they can whitelist paths using `!`.
+	pub fn is_ignored(&mut self, root: &Path, path: &Path, is_dir: bool) -> bool {
+		for dir in path.ancestors().skip(1) {
+			if !dir.starts_with(root) {
+				break;
+			}
+
+			let matched = self
+				.load(dir)
+				.map(|gitignore| gitignore.matched_path_or_any_parents(path, is_dir));
+			match matched {
+				Some(matched) if matched.is_ignore() => return true,
+				Some(matched) if matched.is_whitelist() => return false,
+				_ => {}
+			}
+		}
+
+		false
+	}
+
+	/// Forgets the ignore file of `dir`, so that it gets reloaded the next time it's needed
+	pub fn invalidate(&mut self, dir: &Path) {
+		self.dirs.remove(dir);
+	}
+
+	fn load(&mut self, dir: &Path) -> Option<&Gitignore> {
+		self.dirs
+			.entry(dir.to_owned())
+			.or_insert_with(|| {
+				let path = dir.join(IGNORE_FILE_NAME);
+				if !path.is_file() {
+					return None;
+				}
+
+				// invalid lines are skipped, the valid ones still apply
+				let (gitignore, _) = Gitignore::new(path);
+				Some(gitignore)
+			})
+			.as_ref()
+	}
+}
@@ -1,8 +1,28 @@
+mod chapters;
 mod config;
+mod control;
+mod copy;
+mod cue;
+mod dir_config;
+mod error_log;
+mod events;
+mod ignore_file;
+mod plan;
+mod playlist;
+mod probe;
+mod retry;
+mod segment;
+mod summary;
 mod tag;
+mod tmp_file;
 mod ui;
+#[cfg(target_os = "linux")]
+mod watch;
 
-use crate::config::{Config, Transcode};
+use crate::{
+	config::{Config, SplitCue, StreamSelection, Target, Transcode},
+	probe::LazyProbe,
+};
 use anyhow::{Context, Error, Result};
 use futures::{pin_mut, prelude::*};
 use glib::Boxed;
@@ -10,15 +30,26 @@ use gstreamer::{Element, element_error, prelude::*};
 use gstreamer_base::prelude::*;
 use std::{
 	borrow::Cow,
+	cell::{Cell, RefCell},
+	collections::{BTreeSet, HashMap, HashSet, hash_map::Entry},
 	error::Error as StdError,
-	fmt,
-	fmt::Write as FmtWrite,
+	fmt, iter,
 	path::{Path, PathBuf},
+	process::ExitCode,
+	rc::Rc,
 	result::Result as StdResult,
-	sync::Arc,
-	time::Duration,
+	sync::{
+		Arc, Mutex,
+		atomic::{AtomicUsize, Ordering},
+	},
+	time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+	fs,
+	sync::mpsc::UnboundedReceiver,
+	task,
+	time::{interval, sleep},
 };
-use tokio::{fs, io::AsyncWriteExt, task, time::interval};
 
 #[derive(Clone, Debug, Boxed)]
 #[boxed_type(name = "GBoxErrorWrapper")]
@@ -42,6 +73,13 @@ impl fmt::Display for GBoxErrorWrapper {
 	}
 }
 
+/// The run was aborted because of the `max_errors` option, the failed files are in the summary
+#[derive(Debug, derive_more::Display, derive_more::Error)]
+#[display("Aborted after {} failed file(s)", failed)]
+struct MaxErrorsReached {
+	failed: usize,
+}
+
 #[derive(Debug, derive_more::Display, derive_more::Error)]
 #[display("Received error from {}: {} (debug: {:?})", src, error, debug)]
 struct GErrorMessage {
@@ -76,240 +114,1584 @@ fn gmake<T: IsA<Element>>(factory_name: &str, properties: &[(&str, &dyn ToValue)
 #[derive(Debug, Clone)]
 pub struct ConversionArgs {
 	rel_from_path: PathBuf,
+	/// size of the "from" file in bytes, used to weight the overall progress
+	size: u64,
+	/// one for every target the file needs to be converted for, never empty
+	outputs: Vec<Output>,
+	/// whether the file uses a lossy codec, only known if it was probed
+	is_lossy: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Output {
+	/// index into `Config::targets`
+	target: usize,
 	transcode: Transcode,
+	/// the dir config the transcode comes from, `None` for the main config
+	rule: Option<PathBuf>,
+	/// the part of the file that gets converted, `None` for the whole file
+	segment: Option<segment::Segment>,
+	/// index of the audio stream that gets converted, starting at 0
+	stream: usize,
+	/// id of the audio stream that gets converted, `None` for the first one if the file wasn't
+	/// probed
+	stream_id: Option<String>,
+	/// whether all audio streams are converted, the stream number is added to the file name then
+	split_streams: bool,
+	/// the file is a video, only its audio gets converted
+	video_audio: bool,
+}
+
+/// Jobs for all files that need to be converted and all playlists, and errors for paths that
+/// couldn't be read and for files whose outputs have the same path because of `fat_safe_names`
+fn get_jobs(config: &Config) -> impl Iterator<Item = Result<Job>> + '_ {
+	let mut dir_configs = dir_config::DirConfigs::default();
+	let mut fat_safe_paths = HashMap::new();
+	walk(config, &config.from).flat_map(move |entry| match entry {
+		Ok(entry) if entry.file_type().is_file() => {
+			let job = get_file_job(config, &mut dir_configs, entry.path()).transpose();
+			let collision = match &job {
+				Some(Ok(Job::Convert(args))) => {
+					find_name_collision(&config.targets, &mut fat_safe_paths, args)
+				}
+				_ => None,
+			};
+			job.into_iter()
+				.chain(collision.map(|collision| Err(collision.into())))
+				.collect()
+		}
+		// reports broken dir configs once, instead of for every file in the dir
+		Ok(entry) if entry.file_type().is_dir() => dir_configs
+			.load(&config.from, entry.path())
+			.err()
+			.map(Err)
+			.into_iter()
+			.collect(),
+		Ok(_) => Vec::new(),
+		Err(err) => vec![Err(walk_error(err))],
+	})
+}
+
+/// The error for a path of "from" that couldn't be read
+fn walk_error(err: walkdir::Error) -> Error {
+	let context = match (err.path(), err.loop_ancestor()) {
+		(Some(path), Some(_)) => format!("Symlink loop at {}", path.display()),
+		(Some(path), None) => format!("Unable to read {}", path.display()),
+		(None, _) => String::from("Unable to read directory"),
+	};
+	Error::new(err).context(context)
+}
+
+/// Two files whose outputs get the same path in a target because of `fat_safe_names`, so one
+/// overwrites the other
+#[derive(Debug, derive_more::Display, derive_more::Error)]
+#[display(
+	"{} and {} are both converted to {}",
+	first.display(),
+	second.display(),
+	to.display()
+)]
+struct NameCollision {
+	first: PathBuf,
+	second: PathBuf,
+	to: PathBuf,
+}
+
+/// Remembers the output paths of `args` in the targets with `fat_safe_names`, returns the
+/// collision if another file got one of them already. `paths` maps the output paths to the
+/// relative path of the file they are converted from.
+fn find_name_collision(
+	targets: &[Target],
+	paths: &mut HashMap<PathBuf, PathBuf>,
+	args: &ConversionArgs,
+) -> Option<NameCollision> {
+	for output in &args.outputs {
+		if !targets[output.target].fat_safe_names {
+			continue;
+		}
+
+		let to = get_output_path(targets, &args.rel_from_path, output);
+		match paths.entry(to) {
+			Entry::Vacant(entry) => {
+				entry.insert(args.rel_from_path.clone());
+			}
+			Entry::Occupied(entry) if *entry.get() != args.rel_from_path => {
+				return Some(NameCollision {
+					first: entry.get().clone(),
+					second: args.rel_from_path.clone(),
+					to: entry.key().clone(),
+				});
+			}
+			Entry::Occupied(_) => {}
+		}
+	}
+
+	None
 }
 
-fn get_conversion_args(config: &Config) -> impl Iterator<Item = Result<ConversionArgs>> + '_ {
-	walkdir::WalkDir::new(&config.from)
+/// Walks `dir`, without descending into skipped dirs
+fn walk<'a>(
+	config: &'a Config,
+	dir: &Path,
+) -> impl Iterator<Item = walkdir::Result<walkdir::DirEntry>> + use<'a> {
+	let mut ignore_files = ignore_file::IgnoreFiles::default();
+	walkdir::WalkDir::new(dir)
+		.follow_links(config.walk.follow_symlinks)
+		.same_file_system(config.walk.same_file_system)
 		.into_iter()
-		.filter_map(|e| e.ok())
-		.filter(|e| e.file_type().is_file())
-		.map(move |e| -> Result<Option<ConversionArgs>> {
-			let from_bytes = path_to_bytes(e.path());
+		.filter_entry(move |entry| {
+			!is_skipped(
+				config,
+				&mut ignore_files,
+				entry.path(),
+				entry.file_type().is_dir(),
+			)
+		})
+}
+
+/// Whether `path` is skipped according to the walk options, exclude rules or ignore files
+fn is_skipped(
+	config: &Config,
+	ignore_files: &mut ignore_file::IgnoreFiles,
+	path: &Path,
+	is_dir: bool,
+) -> bool {
+	let rel_path = match path.strip_prefix(&config.from) {
+		Ok(rel_path) => rel_path,
+		Err(_) => return false,
+	};
+
+	if config.walk.skip_hidden
+		&& rel_path
+			.components()
+			.any(|c| c.as_os_str().to_string_lossy().starts_with('.'))
+	{
+		return true;
+	}
+
+	let mut path_bytes = path_to_bytes(path).into_owned();
+	if is_dir {
+		// allows excluding whole dirs with globs like "**/Samples/**"
+		path_bytes.push(b'/');
+	}
+	if config
+		.exclude
+		.iter()
+		.any(|regex| regex.is_match(&path_bytes))
+	{
+		return true;
+	}
+
+	ignore_files.is_ignored(&config.from, path, is_dir)
+}
+
+/// A playlist job if `from_path` is a playlist and playlists are converted, a conversion job if
+/// the file needs to be converted, or `Job::UpToDate` if it was converted already
+fn get_file_job(
+	config: &Config,
+	dir_configs: &mut dir_config::DirConfigs,
+	from_path: &Path,
+) -> Result<Option<Job>> {
+	if config.playlists.is_some() && playlist::is_playlist(from_path) {
+		let rel_from_path = from_path.strip_prefix(&config.from).with_context(|| {
+			format!(
+				"Unable to get relative path for {} from {}",
+				from_path.display(),
+				config.from.display()
+			)
+		})?;
+		return Ok(Some(Job::Playlist {
+			rel_from_path: rel_from_path.to_path_buf(),
+		}));
+	}
+
+	get_file_conversion_job(config, dir_configs, from_path)
+}
+
+fn get_file_conversion_job(
+	config: &Config,
+	dir_configs: &mut dir_config::DirConfigs,
+	from_path: &Path,
+) -> Result<Option<Job>> {
+	let rel_path = from_path.strip_prefix(&config.from).with_context(|| {
+		format!(
+			"Unable to get relative path for {} from {}",
+			from_path.display(),
+			config.from.display()
+		)
+	})?;
+
+	let from_metadata = from_path.metadata().with_context(|| {
+		format!(
+			"Unable to get metadata for \"from\" file {}",
+			from_path.display()
+		)
+	})?;
+	let from_mtime = from_metadata.modified().with_context(|| {
+		format!(
+			"Unable to get mtime for \"from\" file {}",
+			from_path.display()
+		)
+	})?;
+
+	// probing is expensive, files that were converted since they last changed aren't probed
+	match is_up_to_date(config, dir_configs, from_path, rel_path, from_mtime)? {
+		Some(true) => return Ok(Some(Job::UpToDate)),
+		Some(false) => {}
+		None => return Ok(None),
+	}
+
+	let mut probe = LazyProbe::new(from_path);
+	let all_outputs = get_outputs(config, dir_configs, &mut probe)?;
+	if all_outputs.is_empty() {
+		return Ok(None);
+	}
+
+	let mut outputs = Vec::new();
+	for output in all_outputs {
+		let to = get_output_path(&config.targets, rel_path, &output);
+		if !is_output_up_to_date(&to, from_mtime)? {
+			outputs.push(output);
+		}
+	}
+
+	if outputs.is_empty() {
+		return Ok(Some(Job::UpToDate));
+	}
+
+	Ok(Some(Job::Convert(ConversionArgs {
+		rel_from_path: rel_path.to_path_buf(),
+		size: from_metadata.len(),
+		outputs,
+		is_lossy: probe.probed().map(|probe| !probe.lossless),
+	})))
+}
+
+/// Whether `to` exists and was written after the "from" file was last changed
+fn is_output_up_to_date(to: &Path, from_mtime: SystemTime) -> Result<bool> {
+	match to.metadata().and_then(|md| md.modified()) {
+		Ok(to_mtime) => Ok(to_mtime >= from_mtime),
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+		Err(err) => Err(err)
+			.with_context(|| format!("Unable to get mtime for \"to\" file {}", to.display())),
+	}
+}
+
+/// Whether `from_path` was converted for all targets since it was last changed, checked without
+/// probing it.
+///
+/// For every target one of the outputs the file might get depending on its audio stream and tags
+/// needs to be up to date. Files that might be skipped depending on them have no output to
+/// compare with, so they aren't up to date. The tracks of files split by chapters or an embedded
+/// CUE sheet are only known after probing, those are up to date if their dir only contains up to
+/// date tracks. `None` if the file is never converted for any target.
+fn is_up_to_date(
+	config: &Config,
+	dir_configs: &mut dir_config::DirConfigs,
+	from_path: &Path,
+	rel_path: &Path,
+	from_mtime: SystemTime,
+) -> Result<Option<bool>> {
+	let candidates = dir_configs.candidates(config, from_path)?;
+	let mut is_converted = false;
+	for (target_idx, candidates) in candidates.into_iter().enumerate() {
+		// never converted for the target
+		if candidates.iter().all(Option::is_none) {
+			continue;
+		}
+		is_converted = true;
+
+		let mut is_target_up_to_date = false;
+		for candidate in candidates.iter().flatten() {
+			if is_candidate_up_to_date(
+				config, target_idx, candidate, from_path, rel_path, from_mtime,
+			)? {
+				is_target_up_to_date = true;
+				break;
+			}
+		}
+		if !is_target_up_to_date {
+			return Ok(Some(false));
+		}
+	}
+
+	Ok(is_converted.then_some(true))
+}
+
+/// Whether the outputs of `from_path` for `candidate` are up to date, see `is_up_to_date`
+fn is_candidate_up_to_date(
+	config: &Config,
+	target_idx: usize,
+	candidate: &dir_config::TargetTranscode,
+	from_path: &Path,
+	rel_path: &Path,
+	from_mtime: SystemTime,
+) -> Result<bool> {
+	let target = &config.targets[target_idx];
+	let is_copy = matches!(candidate.transcode, Transcode::Copy { .. });
+	let split_streams = candidate.stream == StreamSelection::All && !is_copy;
+	let output = |segment| Output {
+		target: target_idx,
+		transcode: candidate.transcode.clone(),
+		rule: candidate.rule.clone(),
+		segment,
+		stream: 0,
+		stream_id: None,
+		split_streams,
+		video_audio: candidate.video_audio,
+	};
+
+	if candidate.is_split() && !is_copy {
+		// a sidecar CUE sheet is read without probing the file
+		let mut probe = LazyProbe::new(from_path);
+		if let Some(sheet) = cue::find(&mut probe, SplitCue::Sidecar)? {
+			let file_name = from_path.file_name().unwrap_or_default();
+			for segment in sheet.segments(file_name) {
+				let to = get_output_path(&config.targets, rel_path, &output(Some(segment)));
+				if !is_output_up_to_date(&to, from_mtime)? {
+					return Ok(false);
+				}
+			}
+			return Ok(true);
+		}
+
+		if (candidate.split_cue == SplitCue::Embedded || candidate.split_chapters)
+			&& are_segments_up_to_date(target, rel_path, &candidate.transcode, from_mtime)?
+		{
+			return Ok(true);
+		}
+	}
+
+	let to = get_output_path(&config.targets, rel_path, &output(None));
+	is_output_up_to_date(&to, from_mtime)
+}
+
+/// Whether the segment dir of `rel_from_path` contains tracks and all of them are up to date
+fn are_segments_up_to_date(
+	target: &Target,
+	rel_from_path: &Path,
+	transcode: &Transcode,
+	from_mtime: SystemTime,
+) -> Result<bool> {
+	let segment_dir = get_segment_dir(target, rel_from_path);
+	let entries = match std::fs::read_dir(&segment_dir) {
+		Ok(entries) => entries,
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+		Err(err) => {
+			return Err(err)
+				.with_context(|| format!("Unable to read dir {}", segment_dir.display()));
+		}
+	};
+
+	let mut has_segments = false;
+	for entry in entries {
+		let path = entry
+			.with_context(|| format!("Unable to read dir {}", segment_dir.display()))?
+			.path();
+		if path.extension().and_then(|ext| ext.to_str()) != transcode.extension() {
+			continue;
+		}
+		if !is_output_up_to_date(&path, from_mtime)? {
+			return Ok(false);
+		}
+		has_segments = true;
+	}
+
+	Ok(has_segments)
+}
+
+/// All outputs of the file of `probe` for all targets, whether they are up to date or not
+fn get_outputs(
+	config: &Config,
+	dir_configs: &mut dir_config::DirConfigs,
+	probe: &mut LazyProbe,
+) -> Result<Vec<Output>> {
+	let transcodes = dir_configs.transcodes(config, probe)?;
+
+	// the segments found for each split mode, looked up once per file
+	let mut split_segments = HashMap::new();
+
+	let mut outputs = Vec::new();
+	for (target, transcode) in transcodes.into_iter().enumerate() {
+		let transcode = match transcode {
+			Some(transcode) => transcode,
+			None => continue,
+		};
+
+		// copies are never split
+		let segments = match transcode.transcode {
+			Transcode::Copy { .. } => None,
+			_ => match split_segments.entry((transcode.split_cue, transcode.split_chapters)) {
+				Entry::Occupied(entry) => entry.into_mut(),
+				Entry::Vacant(entry) => entry.insert(segment::find(
+					probe,
+					transcode.split_cue,
+					transcode.split_chapters,
+				)?),
+			}
+			.clone(),
+		};
+		let segments = match segments {
+			Some(segments) => segments.into_iter().map(Some).collect(),
+			None => vec![None],
+		};
 
-			let transcode = config
-				.matches
+		let streams = match (&transcode.transcode, &transcode.stream) {
+			// copies contain all streams
+			(Transcode::Copy { .. }, _) => vec![(0, None)],
+			// the file isn't probed just to find its first stream
+			(_, StreamSelection::First) => {
+				let stream_id = probe
+					.probed()
+					.and_then(|probe| probe.audio_streams.first())
+					.and_then(|stream| stream.stream_id.clone());
+				vec![(0, stream_id)]
+			}
+			(_, StreamSelection::Index(index)) => {
+				let audio_streams = &probe.get()?.audio_streams;
+				let stream = index.get() - 1;
+				let audio_stream = audio_streams.get(stream).ok_or_else(|| {
+					Error::msg(format!(
+						"Audio stream {} not found, the file has {} audio streams",
+						index,
+						audio_streams.len()
+					))
+				})?;
+				vec![(stream, audio_stream.stream_id.clone())]
+			}
+			(_, StreamSelection::Language(language)) => {
+				let audio_streams = &probe.get()?.audio_streams;
+				let stream = audio_streams
+					.iter()
+					.position(|stream| {
+						stream.language.as_ref().is_some_and(|stream_language| {
+							stream_language.eq_ignore_ascii_case(language)
+						})
+					})
+					.unwrap_or(0);
+				let stream_id = audio_streams
+					.get(stream)
+					.and_then(|stream| stream.stream_id.clone());
+				vec![(stream, stream_id)]
+			}
+			(_, StreamSelection::All) => probe
+				.get()?
+				.audio_streams
 				.iter()
-				.filter(|m| {
-					m.regexes
-						.iter()
-						.any(|regex| regex.is_match(from_bytes.as_ref()))
-				})
-				.map(|m| m.to.clone())
-				.next();
-			let transcode = if let Some(transcode) = transcode {
-				transcode
+				.map(|stream| stream.stream_id.clone())
+				.enumerate()
+				.collect(),
+		};
+		let split_streams = transcode.stream == StreamSelection::All
+			&& !matches!(transcode.transcode, Transcode::Copy { .. });
+
+		for (segment, (stream, stream_id)) in segments
+			.iter()
+			.flat_map(|segment| streams.iter().map(move |stream| (segment, stream)))
+		{
+			let output = Output {
+				target,
+				transcode: transcode.transcode.clone(),
+				rule: transcode.rule.clone(),
+				segment: segment.clone(),
+				stream: *stream,
+				stream_id: stream_id.clone(),
+				split_streams,
+				video_audio: transcode.video_audio,
+			};
+			outputs.push(output);
+		}
+	}
+
+	Ok(outputs)
+}
+
+fn get_output_path(targets: &[Target], rel_from_path: &Path, output: &Output) -> PathBuf {
+	let target = &targets[output.target];
+	let path = match &output.segment {
+		None => get_to_path(target, rel_from_path, &output.transcode),
+		Some(segment) => get_segment_dir(target, rel_from_path).join(format!(
+			"{}.{}",
+			segment.file_stem(target),
+			output.transcode.extension().unwrap_or_default()
+		)),
+	};
+
+	if output.split_streams {
+		get_stream_path(&path, output.stream)
+	} else {
+		path
+	}
+}
+
+/// `path` with the number of the audio stream added in front of the extension, like "a.2.opus"
+fn get_stream_path(path: &Path, stream: usize) -> PathBuf {
+	let mut file_name = path.file_stem().unwrap_or_default().to_owned();
+	file_name.push(format!(".{}", stream + 1));
+	if let Some(extension) = path.extension() {
+		file_name.push(".");
+		file_name.push(extension);
+	}
+	path.with_file_name(file_name)
+}
+
+/// The dir the segments of a split file are written to, named after the file with the extension
+/// replaced by ".tracks", so that it doesn't collide with the dirs of the "from" dir
+fn get_segment_dir(target: &Target, rel_from_path: &Path) -> PathBuf {
+	let mut dir = target.to.join(get_rel_to_path(target, rel_from_path));
+	dir.set_extension("tracks");
+	dir
+}
+
+fn get_to_path(target: &Target, rel_from_path: &Path, transcode: &Transcode) -> PathBuf {
+	let mut to_path = target.to.join(get_rel_to_path(target, rel_from_path));
+	if let Some(extension) = transcode.extension() {
+		to_path.set_extension(extension);
+	}
+	to_path
+}
+
+/// `rel_from_path` with the file names changed as needed by the target
+fn get_rel_to_path<'a>(target: &Target, rel_from_path: &'a Path) -> Cow<'a, Path> {
+	if !target.fat_safe_names {
+		return Cow::Borrowed(rel_from_path);
+	}
+
+	let path = rel_from_path
+		.components()
+		.map(|c| match c {
+			std::path::Component::Normal(name) => {
+				PathBuf::from(fat_safe_name(&name.to_string_lossy()))
+			}
+			c => PathBuf::from(c.as_os_str()),
+		})
+		.collect();
+	Cow::Owned(path)
+}
+
+/// `name` with the characters FAT filesystems don't allow in file names replaced by "_"
+fn fat_safe_name(name: &str) -> String {
+	let name: String = name
+		.chars()
+		.map(|c| match c {
+			'<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+			c if c.is_control() => '_',
+			c => c,
+		})
+		.collect();
+	// trailing dots and spaces get dropped by FAT filesystems
+	let name = name.trim_end_matches(['.', ' ']);
+	if name.is_empty() {
+		String::from("_")
+	} else {
+		name.to_string()
+	}
+}
+
+fn init_gstreamer() -> Result<()> {
+	gstreamer::init()?;
+	gstreamer::tags::register::<tag::MbArtistId>();
+	gstreamer::tags::register::<tag::MbAlbumArtistId>();
+	Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<ExitCode> {
+	let start = Instant::now();
+
+	let config = config::config().context("Could not get the config")?;
+
+	if config.plan {
+		return plan::print(&config);
+	}
+
+	// stdout is reserved for the events in that case
+	let summary_to_stderr = matches!(config.events, Some(config::EventsOutput::Stdout));
+
+	task::LocalSet::new()
+		.run_until(async move {
+			let (ui_queue, commands, ui_fut) = ui::init(&config)?;
+			let summary = Rc::new(RefCell::new(summary::Summary::default()));
+
+			let summary_clone = summary.clone();
+			let main_handle = async move {
+				let res =
+					task::spawn_local(main_loop(config, ui_queue.clone(), commands, summary_clone))
+						.await
+						.context("Main task failed");
+
+				// the ui needs to shut down in any case, so it can restore the terminal
+				ui_queue.push(ui::Msg::Exit);
+
+				let ok = res??;
+				Result::<_>::Ok(ok)
+			};
+
+			let ui_handle = async move {
+				let ok = task::spawn_local(ui_fut)
+					.await
+					.context("Ui task failed")?
+					.context("Ui failed")?;
+				Result::<_>::Ok(ok)
+			};
+
+			let (main_res, ui_res) = future::join(main_handle, ui_handle).await;
+
+			// the ui is torn down at this point, so we can write to the terminal again
+			let summary = summary.borrow();
+			if summary_to_stderr {
+				summary.print(&mut std::io::stderr(), start.elapsed());
 			} else {
-				return Ok(None);
+				summary.print(&mut std::io::stdout(), start.elapsed());
+			}
+
+			match main_res {
+				// failed files are reported with the exit code of the summary
+				Err(err) if err.is::<MaxErrorsReached>() => eprintln!("Error: {:?}", err),
+				res => res?,
+			}
+			ui_res?;
+
+			if summary.failed.is_empty()
+				&& summary.scan_errors.is_empty()
+				&& summary.name_collisions.is_empty()
+			{
+				Ok(ExitCode::SUCCESS)
+			} else {
+				Ok(ExitCode::from(summary::EXIT_CODE_FAILED))
+			}
+		})
+		.await
+}
+
+async fn main_loop(
+	config: Config,
+	ui_queue: ui::MsgQueue,
+	commands: UnboundedReceiver<control::Command>,
+	summary: Rc<RefCell<summary::Summary>>,
+) -> Result<()> {
+	let (config, error_log) = task::spawn_blocking(move || -> Result<_> {
+		init_gstreamer()?;
+
+		for target in &config.targets {
+			tmp_file::remove_stale(&target.to).context("Failed removing stale temporary files")?;
+		}
+
+		let error_log = error_log::ErrorLog::open(&config.log)?;
+
+		Ok((config, error_log))
+	})
+	.await
+	.context("Init task failed")??;
+
+	#[cfg(not(target_os = "linux"))]
+	if config.watch {
+		return Err(Error::msg("Watch mode is only supported on linux"));
+	}
+
+	// the watches are installed before scanning, that way changes made during the scan aren't
+	// missed
+	#[cfg(target_os = "linux")]
+	let watch = if config.watch {
+		let (job_sender, job_receiver) = tokio::sync::mpsc::unbounded_channel();
+		let watch = watch::Watch::new(&config, job_sender, &ui_queue, &error_log, &summary).await?;
+		Some((watch, job_receiver))
+	} else {
+		None
+	};
+
+	let scan_config = config.clone();
+	let (conv_args, playlists, scan_errors, up_to_date) = task::spawn_blocking(move || {
+		let mut conv_args = Vec::new();
+		let mut playlists = Vec::new();
+		let mut scan_errors = Vec::new();
+		let mut up_to_date = 0;
+		for job in get_jobs(&scan_config) {
+			match job {
+				Ok(Job::Convert(args)) => conv_args.push(args),
+				Ok(Job::UpToDate) => up_to_date += 1,
+				// playlists are written once the conversions are done
+				Ok(job) => playlists.push(job),
+				Err(err) => scan_errors.push(err),
+			}
+		}
+		(conv_args, playlists, scan_errors, up_to_date)
+	})
+	.await
+	.context("Scan task failed")?;
+
+	ui_queue.push(ui::Msg::Init {
+		task_len: conv_args.len(),
+		total_size: conv_args.iter().map(|args| args.size).sum(),
+		scan_errors: scan_errors
+			.iter()
+			.filter(|err| !err.is::<NameCollision>())
+			.count(),
+		log_path: error_log.path().to_path_buf(),
+	});
+	summary.borrow_mut().started = true;
+	summary.borrow_mut().up_to_date = up_to_date;
+
+	for err in scan_errors {
+		report_scan_error(err, &summary, &error_log).await?;
+	}
+
+	let scheduler =
+		control::Scheduler::new(config.jobs.unwrap_or_else(num_cpus::get), ui_queue.clone());
+
+	let jobs = stream::iter(conv_args.into_iter().map(Job::Convert).chain(playlists));
+
+	let run = Box::pin(async {
+		#[cfg(target_os = "linux")]
+		if let Some((watch, mut job_receiver)) = watch {
+			let jobs = jobs.chain(stream::poll_fn(move |cx| job_receiver.poll_recv(cx)));
+
+			// the watcher never stops on its own, so we're done once the jobs are
+			future::try_select(
+				Box::pin(run_jobs(
+					&config, jobs, &ui_queue, &error_log, &scheduler, &summary,
+				)),
+				Box::pin(watch.run()),
+			)
+			.await
+			.map_err(|err| err.factor_first().0)?;
+			return Ok(());
+		}
+
+		run_jobs(&config, jobs, &ui_queue, &error_log, &scheduler, &summary).await
+	});
+
+	let cancel = Box::pin(async {
+		tokio::select! {
+			signal = shutdown_signal() => Err(Error::msg(format!("Interrupted by {}", signal?))),
+			_ = scheduler.aborted() => Err(Error::msg("Aborted by user")),
+			never = scheduler.process_commands(commands) => match never {},
+		}
+	});
+
+	match future::select(run, cancel).await {
+		future::Either::Left((res, _)) => res,
+		future::Either::Right((res, run)) => {
+			// dropping the running jobs stops their pipelines and removes their temporary files
+			drop(run);
+			res
+		}
+	}
+}
+
+/// Adds an error found while scanning "from" to the summary and the error log
+async fn report_scan_error(
+	err: Error,
+	summary: &RefCell<summary::Summary>,
+	error_log: &error_log::ErrorLog,
+) -> Result<()> {
+	{
+		let mut summary = summary.borrow_mut();
+		if err.is::<NameCollision>() {
+			summary.name_collisions.push(err.to_string());
+		} else {
+			summary.scan_errors.push(err.to_string());
+		}
+	}
+
+	let context = error_log::LogContext {
+		action: "scan",
+		from: None,
+		to: None,
+		transcode: None,
+	};
+	error_log.log(context, err).await
+}
+
+/// Resolves to the name of the received signal once the process is asked to shut down
+async fn shutdown_signal() -> Result<&'static str> {
+	#[cfg(unix)]
+	{
+		use tokio::signal::unix::{SignalKind, signal};
+
+		let mut sigint =
+			signal(SignalKind::interrupt()).context("Could not install SIGINT handler")?;
+		let mut sigterm =
+			signal(SignalKind::terminate()).context("Could not install SIGTERM handler")?;
+
+		tokio::select! {
+			_ = sigint.recv() => Ok("SIGINT"),
+			_ = sigterm.recv() => Ok("SIGTERM"),
+		}
+	}
+
+	#[cfg(not(unix))]
+	{
+		tokio::signal::ctrl_c()
+			.await
+			.context("Could not install ctrl-c handler")?;
+		Ok("ctrl-c")
+	}
+}
+
+#[derive(Debug)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+enum Job {
+	Convert(ConversionArgs),
+
+	/// removes the output of a deleted "from" file or dir
+	Remove {
+		rel_from_path: PathBuf,
+		is_dir: bool,
+	},
+
+	/// moves the output of a renamed "from" file or dir
+	Rename {
+		rel_from_path: PathBuf,
+		rel_to_path: PathBuf,
+		is_dir: bool,
+	},
+
+	/// writes a playlist with its entries pointing to the outputs
+	Playlist {
+		rel_from_path: PathBuf,
+	},
+
+	/// a file whose outputs are all up to date, only counted in the summary
+	UpToDate,
+}
+
+impl Job {
+	/// The files or dirs of "from" the job works on
+	fn rel_from_paths(&self) -> Vec<&Path> {
+		match self {
+			Job::Convert(args) => vec![&args.rel_from_path],
+			Job::Remove { rel_from_path, .. } | Job::Playlist { rel_from_path } => {
+				vec![rel_from_path]
+			}
+			Job::Rename {
+				rel_from_path,
+				rel_to_path,
+				..
+			} => vec![rel_from_path, rel_to_path],
+			Job::UpToDate => Vec::new(),
+		}
+	}
+}
+
+async fn run_jobs(
+	config: &Config,
+	jobs: impl Stream<Item = Job>,
+	ui_queue: &ui::MsgQueue,
+	error_log: &error_log::ErrorLog,
+	scheduler: &control::Scheduler,
+	summary: &RefCell<summary::Summary>,
+) -> Result<()> {
+	enum Event<'a> {
+		Finished(usize, Result<()>),
+		Next(Option<(control::Slot<'a>, Job)>),
+	}
+
+	pin_mut!(jobs);
+	let mut running = stream::FuturesUnordered::new();
+	// the "from" paths of the running jobs, by job id
+	let mut running_paths: HashMap<usize, Vec<PathBuf>> = HashMap::new();
+	// ids of the running conversions
+	let mut running_conversions = HashSet::new();
+	// jobs waiting for a running job on the same path, in watch mode a file can change again
+	// while it's still being converted
+	let mut deferred: Vec<Job> = Vec::new();
+	let mut next_id = 0;
+
+	loop {
+		// a slot is acquired before the next job is taken from the queue, that way only the
+		// running jobs exist as futures and they are started in order
+		let next_job = async {
+			let slot = scheduler.acquire().await?;
+
+			let is_blocked = |job: &Job| {
+				// playlists only point to outputs that exist, so they wait for the conversions
+				if let Job::Playlist { .. } = job
+					&& !running_conversions.is_empty()
+				{
+					return true;
+				}
+
+				job.rel_from_paths().iter().any(|path| {
+					running_paths
+						.values()
+						.flatten()
+						.any(|running| running.starts_with(path) || path.starts_with(running))
+				})
+			};
+
+			if let Some(idx) = deferred.iter().position(|job| !is_blocked(job)) {
+				return Some((slot, deferred.remove(idx)));
+			}
+
+			loop {
+				let job = match jobs.next().await {
+					Some(job) => job,
+					None if deferred.is_empty() => return None,
+					// the deferred jobs are started once the jobs they wait for are done
+					None => future::pending().await,
+				};
+				if !is_blocked(&job) {
+					return Some((slot, job));
+				}
+
+				// a newer conversion of the same file replaces the waiting one
+				if let Job::Convert(args) = &job {
+					deferred.retain(|deferred| {
+						!matches!(deferred, Job::Convert(deferred) if deferred.rel_from_path == args.rel_from_path)
+					});
+				}
+				deferred.push(job);
+			}
+		};
+
+		let event = tokio::select! {
+			Some((id, res)) = running.next() => Event::Finished(id, res),
+			next = next_job => Event::Next(next),
+			// in watch mode the queue doesn't end on its own
+			_ = scheduler.stopped() => Event::Next(None),
+		};
+
+		match event {
+			Event::Finished(id, res) => {
+				running_paths.remove(&id);
+				running_conversions.remove(&id);
+				// stops all other running jobs as well
+				res?;
+			}
+			Event::Next(Some((slot, job))) => {
+				running_paths.insert(
+					next_id,
+					job.rel_from_paths()
+						.into_iter()
+						.map(PathBuf::from)
+						.collect(),
+				);
+				let id = next_id;
+				if let Job::Convert(_) = job {
+					running_conversions.insert(id);
+				}
+				running.push(
+					run_job(
+						config, job, id, slot, ui_queue, error_log, scheduler, summary,
+					)
+					.map(move |res| (id, res)),
+				);
+				next_id += 1;
+			}
+			Event::Next(None) => break,
+		}
+	}
+
+	while let Some((_, res)) = running.next().await {
+		res?;
+	}
+
+	// the jobs that are left were never started because of a stop
+	let left = deferred
+		.into_iter()
+		.chain(iter::from_fn(|| jobs.next().now_or_never().flatten()));
+	for job in left {
+		if let Job::Convert(_) = job {
+			summary.borrow_mut().skipped += 1;
+		}
+	}
+
+	Ok(())
+}
+
+/// Runs `job` with the id `i`, holding on to `_slot` until it's done
+#[allow(clippy::too_many_arguments)]
+async fn run_job(
+	config: &Config,
+	job: Job,
+	i: usize,
+	_slot: control::Slot<'_>,
+	ui_queue: &ui::MsgQueue,
+	error_log: &error_log::ErrorLog,
+	scheduler: &control::Scheduler,
+	summary: &RefCell<summary::Summary>,
+) -> Result<()> {
+	match job {
+		Job::Convert(args) => {
+			ui_queue.push(ui::Msg::TaskStart {
+				id: i,
+				args: args.clone(),
+			});
+			let task_start = Instant::now();
+
+			let from_path = config.from.join(&args.rel_from_path);
+			// the groups are converted one after another, the task fails with the first failing
+			// group
+			let groups = group_outputs(&args.outputs);
+
+			let task = async {
+				for group in &groups {
+					let to_path = group_to_path(config, &args, group);
+
+					let mut retry = 0;
+					loop {
+						match transcode(config, &args, group, i, ui_queue).await {
+							Err(err) if retry < config.retries && retry::is_transient(&err) => {
+								retry += 1;
+								let delay = retry::delay(retry);
+								let err = err.context(format!(
+									"Transcoding failed for {}{}, retry {} of {} in {}s",
+									args.rel_from_path.display(),
+									targets_label(config, group),
+									retry,
+									config.retries,
+									delay.as_secs()
+								));
+								let context =
+									transcode_log_context(&from_path, group, to_path.as_deref());
+								error_log
+									.log(context, err)
+									.await
+									.map_err(|err| (err, group))?;
+								sleep(delay).await;
+							}
+							Err(err) => return Err((err, group)),
+							Ok(()) => break,
+						}
+					}
+				}
+				Ok(())
 			};
 
-			let rel_path = e.path().strip_prefix(&config.from).with_context(|| {
-				format!(
-					"Unable to get relative path for {} from {}",
-					e.path().display(),
-					config.from.display()
-				)
-			})?;
+			match scheduler.run_task(i, task).await {
+				None => {
+					summary.borrow_mut().skipped += 1;
+					ui_queue.push(ui::Msg::TaskSkipped { id: i });
+				}
+				Some(Ok(())) => {
+					let (bytes_in, bytes_out) = file_sizes(config, &args).await;
+
+					let mut summary = summary.borrow_mut();
+					for output in &args.outputs {
+						match output.transcode {
+							Transcode::Copy { .. } => summary.copied += 1,
+							_ => summary.converted += 1,
+						}
+					}
+					summary.bytes_in += bytes_in;
+					summary.bytes_out += bytes_out;
+
+					ui_queue.push(ui::Msg::TaskEnd {
+						id: i,
+						output_size: bytes_out,
+						duration: task_start.elapsed(),
+					});
+				}
+				Some(Err((err, group))) => {
+					let failed = {
+						let mut summary = summary.borrow_mut();
+						summary.failed.push(args.rel_from_path.clone());
+						summary.failed.len()
+					};
+
+					let err = err.context(format!(
+						"Transcoding failed for {}{}",
+						args.rel_from_path.display(),
+						targets_label(config, group)
+					));
+					let errors = err.chain().map(|e| e.to_string()).collect();
+					let to_path = group_to_path(config, &args, group);
+					let context = transcode_log_context(&from_path, group, to_path.as_deref());
+					error_log.log(context, err).await?;
+
+					ui_queue.push(ui::Msg::TaskError { id: i, errors });
+
+					// stops all other running tasks as well
+					if config
+						.max_errors
+						.is_some_and(|max_errors| failed >= max_errors)
+					{
+						return Err(MaxErrorsReached { failed }.into());
+					}
+				}
+			}
+		}
+		Job::Remove {
+			rel_from_path,
+			is_dir,
+		} => {
+			if let Err(err) = remove_output(config, &rel_from_path, is_dir).await {
+				let err = err.context(format!(
+					"Removing output failed for {}",
+					rel_from_path.display()
+				));
+				let context = error_log::LogContext {
+					action: "remove",
+					from: Some(&config.from.join(&rel_from_path)),
+					to: None,
+					transcode: None,
+				};
+				error_log.log(context, err).await?;
+			}
+		}
+		Job::Rename {
+			rel_from_path,
+			rel_to_path,
+			is_dir,
+		} => {
+			if let Err((err, to_path)) =
+				rename_output(config, &rel_from_path, &rel_to_path, is_dir).await
+			{
+				let err = err.context(format!(
+					"Renaming output failed for {} to {}",
+					rel_from_path.display(),
+					rel_to_path.display()
+				));
+				// the output path the output was renamed to, if the error is about one
+				let context = error_log::LogContext {
+					action: "rename",
+					from: Some(&config.from.join(&rel_from_path)),
+					to: to_path.as_deref(),
+					transcode: None,
+				};
+				error_log.log(context, err).await?;
+			}
+		}
+		Job::UpToDate => summary.borrow_mut().up_to_date += 1,
+		Job::Playlist { rel_from_path } => {
+			let from_path = config.from.join(&rel_from_path);
+			let context = || error_log::LogContext {
+				action: "playlist",
+				from: Some(&from_path),
+				to: None,
+				transcode: None,
+			};
+
+			// entries might need to be probed, which blocks
+			let task_config = config.clone();
+			let task_rel_from_path = rel_from_path.clone();
+			let failed = summary.borrow().failed.iter().cloned().collect();
+			let res = task::spawn_blocking(move || {
+				let playlist_config = task_config
+					.playlists
+					.as_ref()
+					.expect("playlist jobs are only created with a playlist config");
+				let mut dir_configs = dir_config::DirConfigs::default();
+				playlist::convert(
+					&task_config,
+					playlist_config,
+					&mut dir_configs,
+					&task_rel_from_path,
+					&failed,
+				)
+			})
+			.await
+			.context("Playlist task failed")?;
+
+			match res {
+				Ok(warnings) => {
+					for warning in warnings {
+						summary.borrow_mut().playlist_warnings.push(warning.clone());
+						error_log.log(context(), Error::msg(warning)).await?;
+					}
+				}
+				Err(err) => {
+					summary.borrow_mut().failed.push(rel_from_path);
+					error_log
+						.log(context(), err.context("Writing playlist failed"))
+						.await?;
+				}
+			}
+		}
+	}
+
+	Ok(())
+}
+
+/// Groups the outputs that are converted together. Copies are done on their own, the file is
+/// decoded only once for all other outputs of the same segment.
+fn group_outputs(outputs: &[Output]) -> Vec<Vec<&Output>> {
+	group_by_key(outputs, |output| match output.transcode {
+		Transcode::Copy { .. } => None,
+		_ => Some(output.segment.as_ref().map(segment::Segment::key)),
+	})
+}
+
+/// Groups `items` with the same key in the order they first appear, items without a key get a
+/// group of their own
+fn group_by_key<T, K: PartialEq>(items: &[T], key: impl Fn(&T) -> Option<K>) -> Vec<Vec<&T>> {
+	let mut groups: Vec<(Option<K>, Vec<&T>)> = Vec::new();
+	for item in items {
+		let item_key = key(item);
+		let group = item_key.as_ref().and_then(|item_key| {
+			groups
+				.iter_mut()
+				.find(|(key, _)| key.as_ref() == Some(item_key))
+		});
+		match group {
+			Some((_, group)) => group.push(item),
+			None => groups.push((item_key, vec![item])),
+		}
+	}
+	groups.into_iter().map(|(_, group)| group).collect()
+}
+
+/// The output path of a group of outputs, if it consists of a single one
+fn group_to_path(config: &Config, args: &ConversionArgs, group: &[&Output]) -> Option<PathBuf> {
+	match group {
+		[output] => Some(get_output_path(
+			&config.targets,
+			&args.rel_from_path,
+			output,
+		)),
+		_ => None,
+	}
+}
+
+/// Names of the targets of a group of outputs, for error messages, empty for the unnamed target
+fn targets_label(config: &Config, group: &[&Output]) -> String {
+	let names: Vec<_> = group
+		.iter()
+		.filter_map(|output| config.targets[output.target].name.as_deref())
+		.collect();
+	match names.as_slice() {
+		[] => String::new(),
+		[name] => format!(" (target {})", name),
+		names => format!(" (targets {})", names.join(", ")),
+	}
+}
+
+fn transcode_log_context<'a>(
+	from_path: &'a Path,
+	group: &[&'a Output],
+	to_path: Option<&'a Path>,
+) -> error_log::LogContext<'a> {
+	error_log::LogContext {
+		action: "transcode",
+		from: Some(from_path),
+		to: to_path,
+		// only known if there is a single output
+		transcode: match group {
+			[output] => Some(&output.transcode),
+			_ => None,
+		},
+	}
+}
 
-			let mut to = config.to.join(&rel_path);
-			to.set_extension(transcode.extension());
+/// Size of the "from" file and the summed up size of the "to" files in bytes, missing files are
+/// counted as empty
+async fn file_sizes(config: &Config, args: &ConversionArgs) -> (u64, u64) {
+	let size = |path: PathBuf| async move {
+		fs::metadata(path)
+			.await
+			.map(|md| md.len())
+			.unwrap_or_default()
+	};
 
-			let is_newer = {
-				let from_mtime = e
-					.metadata()
-					.map_err(Error::new)
-					.and_then(|md| md.modified().map_err(Error::new))
-					.with_context(|| {
-						format!(
-							"Unable to get mtime for \"from\" file {}",
-							e.path().display()
-						)
-					})?;
-				let to_mtime = to.metadata().and_then(|md| md.modified());
-				match to_mtime {
-					Ok(to_mtime) => to_mtime < from_mtime,
-					Err(err) if err.kind() == std::io::ErrorKind::NotFound => true,
-					Err(err) => {
-						return Err(err).with_context(|| {
-							format!("Unable to get mtime for \"to\" file {}", to.display())
-						});
-					}
-				}
-			};
+	let to_sizes = args.outputs.iter().map(|output| {
+		size(get_output_path(
+			&config.targets,
+			&args.rel_from_path,
+			output,
+		))
+	});
 
-			if is_newer {
-				Ok(Some(ConversionArgs {
-					rel_from_path: rel_path.to_path_buf(),
-					transcode,
-				}))
-			} else {
-				Ok(None)
-			}
-		})
-		.filter_map(|e| e.transpose())
+	let (from_size, to_sizes) = future::join(
+		size(config.from.join(&args.rel_from_path)),
+		future::join_all(to_sizes),
+	)
+	.await;
+	(from_size, to_sizes.into_iter().sum())
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
-	task::LocalSet::new()
-		.run_until(async move {
-			let (ui_queue, ui_fut) = ui::init();
+async fn remove_output(config: &Config, rel_from_path: &Path, is_dir: bool) -> Result<()> {
+	if !is_dir && config.playlists.is_some() && playlist::is_playlist(rel_from_path) {
+		for target in &config.targets {
+			match fs::remove_file(playlist::get_to_path(target, rel_from_path)).await {
+				Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+				_ => {}
+			}
+		}
+		return Ok(());
+	}
 
-			let main_handle = async move {
-				let ok = task::spawn_local(main_loop(ui_queue))
-					.await
-					.context("Main task failed")??;
-				Result::<_>::Ok(ok)
-			};
+	let transcodes = if is_dir {
+		Vec::new()
+	} else {
+		let mut dir_configs = dir_config::DirConfigs::default();
+		dir_configs.transcodes_by_path(config, &config.from.join(rel_from_path))?
+	};
 
-			let ui_handle = async move {
-				let ok = task::spawn_local(ui_fut)
+	for (i, target) in config.targets.iter().enumerate() {
+		let res = if is_dir {
+			fs::remove_dir_all(target.to.join(get_rel_to_path(target, rel_from_path))).await
+		} else {
+			match &transcodes[i] {
+				Some(transcode) if transcode.is_split() => {
+					// the file might have been split, its segments are in a dir of their own
+					remove_segments(
+						&get_segment_dir(target, rel_from_path),
+						&transcode.transcode,
+					)
+					.await?;
+					remove_file_and_streams(
+						&get_to_path(target, rel_from_path, &transcode.transcode),
+						&transcode.stream,
+					)
 					.await
-					.context("Ui task failed")?
-					.context("Ui failed")?;
-				Result::<_>::Ok(ok)
-			};
+				}
+				Some(transcode) => {
+					remove_file_and_streams(
+						&get_to_path(target, rel_from_path, &transcode.transcode),
+						&transcode.stream,
+					)
+					.await
+				}
+				None => continue,
+			}
+		};
 
-			future::try_join(main_handle, ui_handle).await?;
-			Ok(())
-		})
-		.await
-}
+		match res {
+			Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+			_ => {}
+		}
+	}
 
-async fn main_loop(ui_queue: ui::MsgQueue) -> Result<()> {
-	let (config, conv_args) = task::spawn_blocking(|| -> Result<_> {
-		gstreamer::init()?;
-		gstreamer::tags::register::<tag::MbArtistId>();
-		gstreamer::tags::register::<tag::MbAlbumArtistId>();
+	Ok(())
+}
 
-		let config = config::config().context("Could not get the config")?;
+/// Removes the segments of a split file from `segment_dir`, and the dir if nothing else is left
+/// in it
+async fn remove_segments(segment_dir: &Path, transcode: &Transcode) -> std::io::Result<()> {
+	let mut entries = match fs::read_dir(segment_dir).await {
+		Ok(entries) => entries,
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+		Err(err) => return Err(err),
+	};
 
-		let conv_args = get_conversion_args(&config)
-			.collect::<Result<Vec<_>>>()
-			.context("Failed loading dir structure")?;
+	let extension = transcode.extension();
+	while let Some(entry) = entries.next_entry().await? {
+		let path = entry.path();
+		if entry.file_type().await?.is_file()
+			&& path.extension().and_then(|ext| ext.to_str()) == extension
+		{
+			fs::remove_file(&path).await?;
+		}
+	}
 
-		Ok((config, conv_args))
-	})
-	.await
-	.context("Init task failed")??;
+	match fs::remove_dir(segment_dir).await {
+		Err(err) if err.kind() == std::io::ErrorKind::DirectoryNotEmpty => Ok(()),
+		res => res,
+	}
+}
 
-	let log_path = Path::new(".")
-		.canonicalize()
-		.context("Unable to canonicalize path to log file")?
-		.join("audio-conv.log");
+/// Removes `to_path`, and the outputs of its audio streams if all of them are converted
+async fn remove_file_and_streams(to_path: &Path, stream: &StreamSelection) -> std::io::Result<()> {
+	if *stream == StreamSelection::All {
+		for stream_path in get_existing_stream_paths(to_path).await? {
+			fs::remove_file(stream_path).await?;
+		}
+	}
+	fs::remove_file(to_path).await
+}
 
-	ui_queue.push(ui::Msg::Init {
-		task_len: conv_args.len(),
-		log_path: log_path.clone(),
-	});
+/// The outputs of all audio streams of `to_path` that exist
+async fn get_existing_stream_paths(to_path: &Path) -> std::io::Result<Vec<PathBuf>> {
+	let mut paths = Vec::new();
+	loop {
+		let path = get_stream_path(to_path, paths.len());
+		if !fs::try_exists(&path).await? {
+			return Ok(paths);
+		}
+		paths.push(path);
+	}
+}
 
-	let concurrent_jobs = config.jobs.unwrap_or_else(|| num_cpus::get());
+async fn rename_output(
+	config: &Config,
+	rel_from_path: &Path,
+	rel_to_path: &Path,
+	is_dir: bool,
+) -> StdResult<(), (Error, Option<PathBuf>)> {
+	if !is_dir && config.playlists.is_some() && playlist::is_playlist(rel_from_path) {
+		// the relative entries might not fit the new location, the renamed playlist gets written
+		// as a new one
+		return remove_output(config, rel_from_path, is_dir)
+			.await
+			.map_err(|err| (err, None));
+	}
 
-	stream::iter(conv_args.into_iter().enumerate())
-		.map(Ok)
-		.try_for_each_concurrent(concurrent_jobs, |(i, args)| {
-			let config = &config;
-			let ui_queue = &ui_queue;
-			let log_path = &log_path;
+	let (old_transcodes, new_transcodes) = if is_dir {
+		(Vec::new(), Vec::new())
+	} else {
+		// the renamed file might need to be probed, which blocks
+		let task_config = config.clone();
+		let old_path = config.from.join(rel_from_path);
+		let new_path = config.from.join(rel_to_path);
+		task::spawn_blocking(move || -> Result<_> {
+			let mut dir_configs = dir_config::DirConfigs::default();
+			Ok((
+				dir_configs.transcodes_by_path(&task_config, &old_path)?,
+				dir_configs.transcodes(&task_config, &mut LazyProbe::new(&new_path))?,
+			))
+		})
+		.await
+		.context("Rename task failed")
+		.and_then(|res| res)
+		.map_err(|err| (err, None))?
+	};
 
-			async move {
-				ui_queue.push(ui::Msg::TaskStart {
-					id: i,
-					args: args.clone(),
-				});
+	for (i, target) in config.targets.iter().enumerate() {
+		let renames = if is_dir {
+			vec![(
+				target.to.join(get_rel_to_path(target, rel_from_path)),
+				target.to.join(get_rel_to_path(target, rel_to_path)),
+			)]
+		} else {
+			let old_transcode = match &old_transcodes[i] {
+				Some(transcode) => transcode,
+				None => continue,
+			};
 
-				match transcode(config, &args, i, ui_queue).await {
-					Ok(()) => ui_queue.push(ui::Msg::TaskEnd { id: i }),
-					Err(err) => {
-						let err = err.context(format!(
-							"Transcoding failed for {}",
-							args.rel_from_path.display()
+			if old_transcode.is_split() {
+				// the file might have been split, the dir with its segments is moved along
+				let old_dir = get_segment_dir(target, rel_from_path);
+				let new_dir = get_segment_dir(target, rel_to_path);
+				match fs::rename(&old_dir, &new_dir).await {
+					Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+						let err = Error::new(err).context(format!(
+							"Could not rename {} to {}",
+							old_dir.display(),
+							new_dir.display()
 						));
+						return Err((err, Some(new_dir)));
+					}
+					_ => {}
+				}
+			}
 
-						let mut log_file = match fs::OpenOptions::new()
-							.create(true)
-							.append(true)
-							.open(log_path)
-							.await
-						{
-							Ok(log_file) => log_file,
-							Err(fs_err) => {
-								let err = err.context(fs_err).context("Unable to open log file");
-								return Err(err);
-							}
-						};
-
-						let mut err_str = String::new();
-						if let Err(write_err) = write!(&mut err_str, "{:?}\n", err) {
-							let err = err.context(format!(
-								"Unable to format transcoding error for logging (write error: {})",
-								write_err
-							));
-							return Err(err);
+			let old_stream = &old_transcode.stream;
+			let old_transcode = &old_transcode.transcode;
+			let old_path = get_to_path(target, rel_from_path, old_transcode);
+			let new_transcode = new_transcodes[i].as_ref().filter(|t| {
+				t.transcode.extension() == old_transcode.extension() && t.stream == *old_stream
+			});
+			let new_transcode = match new_transcode {
+				Some(transcode) => &transcode.transcode,
+				None => {
+					// the output doesn't fit the new file name, it gets reconverted if needed
+					match remove_file_and_streams(&old_path, old_stream).await {
+						Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+							let err = Error::new(err)
+								.context(format!("Could not remove {}", old_path.display()));
+							return Err((err, None));
 						}
-
-						log_file
-							.write_all(err_str.as_ref())
-							.await
-							.map_err(|fs_err| {
-								err.context(format!(
-									"Unable to write transcoding error to log file (fs error: {})",
-									fs_err
-								))
-							})?;
-
-						ui_queue.push(ui::Msg::TaskError { id: i });
+						_ => continue,
 					}
 				}
+			};
+			let new_path = get_to_path(target, rel_to_path, new_transcode);
 
-				Result::<_>::Ok(())
+			let mut renames = Vec::new();
+			if *old_stream == StreamSelection::All {
+				let stream_paths = get_existing_stream_paths(&old_path)
+					.await
+					.with_context(|| format!("Could not read outputs of {}", old_path.display()))
+					.map_err(|err| (err, Some(new_path.clone())))?;
+				renames.extend(
+					stream_paths
+						.into_iter()
+						.enumerate()
+						.map(|(stream, path)| (path, get_stream_path(&new_path, stream))),
+				);
 			}
-		})
-		.await?;
+			renames.push((old_path, new_path));
+			renames
+		};
+
+		for (old_path, new_path) in renames {
+			let res = async {
+				if let Some(parent) = new_path.parent() {
+					fs::create_dir_all(parent)
+						.await
+						.with_context(|| format!("Could not create dir {}", parent.display()))?;
+				}
 
-	ui_queue.push(ui::Msg::Exit);
+				match fs::rename(&old_path, &new_path).await {
+					// nothing to move, the "from" file gets converted as a new file
+					Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+					res => res.with_context(|| {
+						format!(
+							"Could not rename {} to {}",
+							old_path.display(),
+							new_path.display()
+						)
+					}),
+				}
+			};
+			res.await.map_err(|err| (err, Some(new_path.clone())))?;
+		}
+	}
 
 	Ok(())
 }
 
+/// Converts `args` for `outputs`, which is either a single copy or any number of outputs that are
+/// transcoded by gstreamer, decoding the file only once
 async fn transcode(
 	config: &Config,
 	args: &ConversionArgs,
+	outputs: &[&Output],
 	task_id: usize,
 	queue: &ui::MsgQueue,
 ) -> Result<()> {
 	let from_path = config.from.join(&args.rel_from_path);
-	let mut to_path = config.to.join(&args.rel_from_path);
-
-	fs::create_dir_all(
-		to_path
-			.parent()
-			.with_context(|| format!("Could not get parent dir for {}", to_path.display()))?,
-	)
-	.await?;
+	let to_paths: Vec<_> = outputs
+		.iter()
+		.map(|output| get_output_path(&config.targets, &args.rel_from_path, output))
+		.collect();
+
+	for to_path in &to_paths {
+		fs::create_dir_all(
+			to_path
+				.parent()
+				.with_context(|| format!("Could not get parent dir for {}", to_path.display()))?,
+		)
+		.await?;
+	}
 
 	// encode into a tmp file first, then rename to actuall file name, that way we're writing
 	// "whole" files to the intended file path, ignoring partial files in the mtime check
-	let to_path_tmp = to_path.with_extension("tmp");
-
-	rm_file_on_err(&to_path_tmp, async {
-		match args.transcode {
-			Transcode::Copy => {
-				fs::copy(&from_path, &to_path_tmp).await.with_context(|| {
-					format!(
-						"Could not copy file from {} to {}",
-						from_path.display(),
-						to_path_tmp.display()
-					)
-				})?;
+	let tmp_files: Vec<_> = to_paths
+		.iter()
+		.map(|to_path| tmp_file::TmpFile::new(to_path))
+		.collect();
+	let to_paths_tmp: Vec<_> = tmp_files.iter().map(|tmp_file| tmp_file.path()).collect();
+
+	rm_file_on_err(&to_paths_tmp, async {
+		match outputs {
+			[
+				Output {
+					transcode: Transcode::Copy { method },
+					..
+				},
+			] => {
+				copy::copy(*method, &from_path, &to_paths[0], to_paths_tmp[0]).await?;
 			}
 			_ => {
-				to_path.set_extension(args.transcode.extension());
-
+				let branches: Vec<_> = outputs
+					.iter()
+					.zip(&to_paths_tmp)
+					.map(|(output, to_path_tmp)| Branch {
+						to_path: to_path_tmp.to_path_buf(),
+						transcode: output.transcode.clone(),
+						stream: output.stream,
+						stream_id: output.stream_id.clone(),
+						container_tags_only: output.video_audio,
+					})
+					.collect();
 				transcode_gstreamer(
 					&from_path,
-					&to_path_tmp,
-					args.transcode.clone(),
+					branches,
+					outputs[0].segment.as_ref(),
+					config.timeout,
+					config.stall_timeout,
 					task_id,
 					queue,
 				)
@@ -317,31 +1699,53 @@ async fn transcode(
 			}
 		}
 
-		fs::rename(&to_path_tmp, &to_path).await.with_context(|| {
-			format!(
-				"Could not rename temporary file {} to {}",
-				to_path_tmp.display(),
-				to_path.display()
-			)
-		})
+		for (to_path_tmp, to_path) in to_paths_tmp.iter().zip(&to_paths) {
+			fs::rename(to_path_tmp, to_path).await.with_context(|| {
+				format!(
+					"Could not rename temporary file {} to {}",
+					to_path_tmp.display(),
+					to_path.display()
+				)
+			})?;
+		}
+
+		Ok(())
 	})
 	.await
 }
 
+/// Encoder branch of `transcode_gstreamer`, writing a single output
+struct Branch {
+	to_path: PathBuf,
+	transcode: Transcode,
+	/// index of the audio stream that gets encoded, only used in messages
+	stream: usize,
+	/// id of the audio stream that gets encoded, `None` for the first audio stream
+	stream_id: Option<String>,
+	/// whether the tags of the audio stream are dropped, keeping the ones of the container, like
+	/// the title of a video
+	container_tags_only: bool,
+}
+
+/// Decodes `from_path` once and encodes it for every branch, only the part of `segment` if given
 async fn transcode_gstreamer(
 	from_path: &Path,
-	to_path: &Path,
-	transcode: Transcode,
+	branches: Vec<Branch>,
+	segment: Option<&segment::Segment>,
+	timeout: Option<Duration>,
+	stall_timeout: Option<Duration>,
 	task_id: usize,
 	queue: &ui::MsgQueue,
 ) -> Result<()> {
 	let file_src: Element = gmake("filesrc", &[("location", &from_path)])?;
 
 	let decodebin: Element = gmake("decodebin", &[])?;
+	skip_video_decoding(&decodebin);
 
 	let src_elems: &[&Element] = &[&file_src, &decodebin];
 
 	let pipeline = gstreamer::Pipeline::new();
+	let _pipeline_guard = PipelineGuard(pipeline.clone());
 
 	pipeline.add_many(src_elems)?;
 	Element::link_many(src_elems)?;
@@ -349,7 +1753,18 @@ async fn transcode_gstreamer(
 	// downgrade pipeline RC to a weak RC to break the reference cycle
 	let pipeline_weak = pipeline.downgrade();
 
-	let to_path_clone = to_path.to_owned();
+	let segment_tags = segment.map(|segment| segment.tags.clone());
+	// number of audio streams the file has, counted as their pads get added
+	let audio_streams = Arc::new(AtomicUsize::new(0));
+	let audio_streams_clone = audio_streams.clone();
+	// the streams of the branches, which are removed once their pad got added
+	let missing_streams = Arc::new(Mutex::new(
+		branches
+			.iter()
+			.map(|branch| branch.stream)
+			.collect::<BTreeSet<_>>(),
+	));
+	let missing_streams_clone = missing_streams.clone();
 	decodebin.connect_pad_added(move |decodebin, src_pad| {
 		let insert_sink = || -> Result<()> {
 			let pipeline = match pipeline_weak.upgrade() {
@@ -374,108 +1789,157 @@ async fn transcode_gstreamer(
 					)));
 				}
 				Some(false) => {
-					// not audio pad... ignoring
-					return Ok(());
+					// not audio pad, like the video of a video file, it still needs to be consumed
+					// for the pipeline to not stall
+					return discard_pad(&pipeline, src_pad);
 				}
 				Some(true) => {}
 			}
 
-			let resample: Element = gmake(
-				"audioresample",
-				&[
-					// quality from 0 to 10
-					("quality", &10i32),
-				],
-			)?;
+			// pads get added in no particular order, so the streams are told apart by their id
+			let is_first = audio_streams_clone.fetch_add(1, Ordering::SeqCst) == 0;
+			let stream_id = src_pad.stream_id();
+			let branches: Vec<_> = branches
+				.iter()
+				.filter(|branch| match &branch.stream_id {
+					Some(id) => stream_id.as_deref() == Some(id.as_str()),
+					None => is_first,
+				})
+				.collect();
+			{
+				let mut missing_streams = missing_streams_clone.lock().unwrap();
+				for branch in &branches {
+					missing_streams.remove(&branch.stream);
+				}
+			}
+			if branches.is_empty() {
+				// the stream isn't converted, it still needs to be consumed
+				return discard_pad(&pipeline, src_pad);
+			}
 
-			let mut dest_elems = vec![
-				resample,
-				// `audioconvert` converts audio format, bitdepth, ...
-				gmake("audioconvert", &[])?,
-			];
-
-			match &transcode {
-				Transcode::Opus {
-					bitrate,
-					bitrate_type,
-				} => {
-					let encoder: Element = gmake(
-						"opusenc",
-						&[
-							(
-								"bitrate",
-								&i32::from(*bitrate)
-									.checked_mul(1_000)
-									.context("Bitrate overflowed")?,
-							),
-							(
-								"bitrate-type",
-								match bitrate_type {
-									config::BitrateType::Vbr => &"1",
-									config::BitrateType::Cbr => &"0",
-								},
-							),
-						],
-					)?;
-
-					dest_elems.push(encoder);
-					dest_elems.push(gmake("oggmux", &[])?);
-				}
-
-				Transcode::Flac { compression } => {
-					let encoder: Element =
-						gmake("flacenc", &[("quality", &compression.to_string())])?;
-					dest_elems.push(encoder);
-				}
-
-				Transcode::Mp3 {
-					bitrate,
-					bitrate_type,
-				} => {
-					let encoder: Element = gmake(
-						"lamemp3enc",
-						&[
-							// target: "1" = "bitrate"
-							("target", &"1"),
-							("bitrate", &i32::from(*bitrate)),
-							(
-								"cbr",
-								match bitrate_type {
-									config::BitrateType::Vbr => &false,
-									config::BitrateType::Cbr => &true,
-								},
-							),
-						],
-					)?;
-
-					dest_elems.push(encoder);
-					dest_elems.push(gmake("id3v2mux", &[])?);
-				}
-
-				Transcode::Copy => {
-					// already handled outside this fn
-					unreachable!();
+			// elements that get the chapters of the file, either as TOC or as tags
+			let mut toc_setters = Vec::new();
+			let mut comment_setters = Vec::new();
+
+			// `audioconvert` converts audio format, bitdepth, ...
+			let convert: Element = gmake("audioconvert", &[])?;
+			let tee: Element = gmake("tee", &[])?;
+			let src_elems = [&convert, &tee];
+			pipeline.add_many(src_elems)?;
+			Element::link_many(src_elems)?;
+
+			// every branch converts the decoded audio into the format its encoder needs on its
+			// own, the queue decouples the encoders from each other
+			for branch in branches {
+				let transcode = &branch.transcode;
+				let resample: Element = gmake(
+					"audioresample",
+					&[
+						// quality from 0 to 10
+						("quality", &10i32),
+					],
+				)?;
+
+				let mut dest_elems =
+					vec![gmake("queue", &[])?, resample, gmake("audioconvert", &[])?];
+				dest_elems.extend(encoder_elems(transcode)?);
+
+				if let Some(tags) = &segment_tags {
+					// the tags of the segment replace the ones of the file with the same name
+					for tag_setter in dest_elems
+						.iter()
+						.filter_map(|e| e.dynamic_cast_ref::<gstreamer::TagSetter>())
+					{
+						tag_setter.merge_tags(tags, gstreamer::TagMergeMode::Replace);
+						tag_setter.set_tag_merge_mode(gstreamer::TagMergeMode::Keep);
+					}
+				} else {
+					// chapters are stored as vorbis comments in Ogg and FLAC files
+					let has_chapter_comments =
+						matches!(transcode, Transcode::Opus { .. } | Transcode::Flac { .. });
+					for e in &dest_elems {
+						if e.is::<gstreamer::TocSetter>() {
+							toc_setters.push(e.clone());
+						} else if has_chapter_comments && e.is::<gstreamer::TagSetter>() {
+							comment_setters.push(e.clone());
+						}
+					}
 				}
-			};
 
-			let file_dest: gstreamer_base::BaseSink =
-				gmake("filesink", &[("location", &to_path_clone)])?;
-			file_dest.set_sync(false);
-			dest_elems.push(file_dest.upcast());
+				if branch.container_tags_only {
+					let queue_pad = dest_elems[0]
+						.static_pad("sink")
+						.expect("queue has no sinkpad");
+					queue_pad.add_probe(gstreamer::PadProbeType::EVENT_DOWNSTREAM, |_, info| {
+						match info.event().map(|event| event.view()) {
+							Some(gstreamer::EventView::Tag(event))
+								if event.tag().scope() == gstreamer::TagScope::Stream =>
+							{
+								gstreamer::PadProbeReturn::Drop
+							}
+							_ => gstreamer::PadProbeReturn::Ok,
+						}
+					});
+				}
+
+				let file_dest: gstreamer_base::BaseSink =
+					gmake("filesink", &[("location", &branch.to_path)])?;
+				file_dest.set_sync(false);
+				dest_elems.push(file_dest.upcast());
 
-			let dest_elem_refs: Vec<_> = dest_elems.iter().collect();
-			pipeline.add_many(&dest_elem_refs)?;
-			Element::link_many(&dest_elem_refs)?;
+				let dest_elem_refs: Vec<_> = dest_elems.iter().collect();
+				pipeline.add_many(&dest_elem_refs)?;
+				Element::link_many(&dest_elem_refs)?;
+				tee.link(&dest_elems[0])?;
 
-			for e in &dest_elems {
+				for e in &dest_elems {
+					e.sync_state_with_parent()?;
+				}
+			}
+
+			for e in src_elems {
 				e.sync_state_with_parent()?;
 			}
 
-			let sink_pad = dest_elems
-				.get(0)
-				.unwrap()
+			let set_toc = move |toc: &gstreamer::TocRef| {
+				for e in &toc_setters {
+					if let Some(toc_setter) = e.dynamic_cast_ref::<gstreamer::TocSetter>() {
+						toc_setter.set_toc(Some(&toc.to_owned()));
+					}
+				}
+
+				let comments = chapters::comments(&chapters::from_toc(toc));
+				for e in &comment_setters {
+					if let Some(tag_setter) = e.dynamic_cast_ref::<gstreamer::TagSetter>() {
+						tag_setter.merge_tags(&comments, gstreamer::TagMergeMode::Replace);
+						tag_setter.set_tag_merge_mode(gstreamer::TagMergeMode::Append);
+					}
+				}
+			};
+
+			// the TOC is usually known by now, FLAC writes its tags as soon as the stream starts
+			if let Some(event) = src_pad.sticky_event::<gstreamer::event::Toc>(0) {
+				set_toc(event.toc().0);
+			}
+
+			let is_segment = segment_tags.is_some();
+			src_pad.add_probe(gstreamer::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+				match info.event().map(|event| event.view()) {
+					// a segment is a single chapter of the file
+					Some(gstreamer::EventView::Toc(_)) if is_segment => {
+						gstreamer::PadProbeReturn::Drop
+					}
+					Some(gstreamer::EventView::Toc(event)) => {
+						set_toc(event.toc().0);
+						gstreamer::PadProbeReturn::Ok
+					}
+					_ => gstreamer::PadProbeReturn::Ok,
+				}
+			});
+
+			let sink_pad = convert
 				.static_pad("sink")
-				.expect("1. dest element has no sinkpad");
+				.expect("audioconvert has no sinkpad");
 			src_pad.link(&sink_pad)?;
 
 			Ok(())
@@ -496,76 +1960,62 @@ async fn transcode_gstreamer(
 	});
 
 	let bus = pipeline.bus().context("Could not get bus for pipeline")?;
+	let mut messages = bus.stream();
 
-	pipeline
-		.set_state(gstreamer::State::Playing)
-		.context("Unable to set the pipeline to the `Playing` state")?;
+	// seeking is only possible once the pipeline is prerolled, it's started once the seek is done
+	let start_state = match segment {
+		Some(_) => gstreamer::State::Paused,
+		None => gstreamer::State::Playing,
+	};
+	pipeline.set_state(start_state).with_context(|| {
+		format!(
+			"Unable to set the pipeline to the `{:?}` state",
+			start_state
+		)
+	})?;
+
+	// whether the pipeline is ready to play, shared with the stall detection
+	let is_prerolled = Cell::new(false);
 
 	let stream_processor = async {
-		bus.stream()
-			.map::<Result<bool>, _>(|msg| {
-				use gstreamer::MessageView;
-
-				match msg.view() {
-					// MessageView::Progress() => {
-
-					// }
-					MessageView::Eos(..) => {
-						// we need to actively stop pulling the stream, that's because stream will
-						// never end despite yielding an `Eos` message
-						Ok(false)
-					}
-					MessageView::Error(err) => {
-						let pipe_stop_res = pipeline.set_state(gstreamer::State::Null);
-
-						let err: Error = err
-							.details()
-							.and_then(|details| {
-								if details.name() != "error-details" {
-									return None;
-								}
-
-								let err = details
-									.get::<&GBoxErrorWrapper>("error")
-									.unwrap()
-									.clone()
-									.into();
-								Some(err)
-							})
-							.unwrap_or_else(|| {
-								GErrorMessage {
-									src: msg
-										.src()
-										.map(|s| String::from(s.path_string()))
-										.unwrap_or_else(|| String::from("None")),
-									error: err.error().to_string(),
-									debug: err.debug().map(|gstring| gstring.into()),
-									source: err.error(),
-								}
-								.into()
-							});
-
-						if let Err(pipe_err) = pipe_stop_res {
-							let err = err.context(pipe_err).context(
-								"Unable to set the pipeline to the `Null` state, after error",
-							);
-							Err(err)
-						} else {
-							Err(err)
-						}
+		use gstreamer::MessageView;
+
+		while let Some(msg) = messages.next().await {
+			match msg.view() {
+				MessageView::AsyncDone(..) if !is_prerolled.get() => {
+					is_prerolled.set(true);
+					if let Some(segment) = segment {
+						seek_segment(&pipeline, segment)?;
+						pipeline
+							.set_state(gstreamer::State::Playing)
+							.context("Unable to set the pipeline to the `Playing` state")?;
 					}
-					_ => Ok(true),
 				}
-			})
-			.take_while(|e| {
-				if let Ok(false) = e {
-					futures::future::ready(false)
-				} else {
-					futures::future::ready(true)
+				MessageView::Eos(..) if segment.is_some() && !is_prerolled.get() => {
+					return Err(Error::msg(
+						"Reached the end of the file before the track could be seeked to",
+					));
 				}
-			})
-			.try_for_each(|_| futures::future::ready(Ok(())))
-			.await?;
+				MessageView::Eos(..) => {
+					// we need to actively stop pulling the stream, that's because stream will
+					// never end despite yielding an `Eos` message
+					return Ok(());
+				}
+				MessageView::Error(err) => {
+					let pipe_stop_res = pipeline.set_state(gstreamer::State::Null);
+
+					let err = message_error(&msg, err);
+
+					if let Err(pipe_err) = pipe_stop_res {
+						return Err(err.context(pipe_err).context(
+							"Unable to set the pipeline to the `Null` state, after error",
+						));
+					}
+					return Err(err);
+				}
+				_ => {}
+			}
+		}
 
 		Result::<_>::Ok(())
 	};
@@ -575,30 +2025,67 @@ async fn transcode_gstreamer(
 	let progress_processor = async {
 		use gstreamer::ClockTime;
 
+		let start = Instant::now();
+		let mut last_pos = None;
+		let mut last_pos_change = start;
+
 		loop {
 			progress_interval.tick().await;
 
-			let dur = decodebin
-				.query_duration::<ClockTime>()
+			let pos = decodebin
+				.query_position::<ClockTime>()
 				.map(|time| time.nseconds());
 
-			let ratio = dur.and_then(|dur| {
-				if dur == 0 {
-					return None;
-				}
+			// the pipeline is set to `Null` by the guard once we return
+			if pos != last_pos {
+				last_pos = pos;
+				last_pos_change = Instant::now();
+			} else if let Some(stall_timeout) = stall_timeout.filter(|stall_timeout| {
+				// some files never report a position, those can only stall while prerolling
+				(last_pos.is_some() || !is_prerolled.get())
+					&& last_pos_change.elapsed() > *stall_timeout
+			}) {
+				return Err(Error::msg(format!(
+					"Timed out, no progress for {} seconds",
+					stall_timeout.as_secs()
+				)));
+			}
 
-				let pos = decodebin
-					.query_position::<ClockTime>()
-					.map(|time| time.nseconds());
+			if let Some(timeout) = timeout.filter(|timeout| start.elapsed() > *timeout) {
+				return Err(Error::msg(format!(
+					"Timed out after {} seconds",
+					timeout.as_secs()
+				)));
+			}
 
-				pos.map(|pos| {
-					let ratio = pos as f64 / dur as f64;
-					ratio.clamp(0.0, 1.0)
-				})
+			let dur = decodebin
+				.query_duration::<ClockTime>()
+				.map(|time| time.nseconds());
+
+			// the progress of a segment is relative to its start and end
+			let (segment_start, segment_end) = match segment {
+				Some(segment) => (
+					segment.start.as_nanos() as u64,
+					segment.end.map(|end| end.as_nanos() as u64).or(dur),
+				),
+				None => (0, dur),
+			};
+			let progress = segment_end.zip(pos).and_then(|(segment_end, pos)| {
+				let dur = segment_end
+					.checked_sub(segment_start)
+					.filter(|dur| *dur > 0)?;
+				let pos = pos.saturating_sub(segment_start);
+
+				let ratio = pos as f64 / dur as f64;
+				Some((ratio.clamp(0.0, 1.0), Duration::from_nanos(pos)))
 			});
 
-			if let Some(ratio) = ratio {
-				queue.push(ui::Msg::TaskProgress { id: task_id, ratio });
+			if let Some((ratio, position)) = progress {
+				queue.push(ui::Msg::TaskProgress {
+					id: task_id,
+					ratio,
+					position,
+				});
 			}
 		}
 
@@ -611,6 +2098,15 @@ async fn transcode_gstreamer(
 		.await
 		.map_err(|err| err.factor_first().0)?;
 
+	let audio_streams = audio_streams.load(Ordering::SeqCst);
+	if let Some(stream) = missing_streams.lock().unwrap().first() {
+		return Err(Error::msg(format!(
+			"Audio stream {} not found, the file has {} audio streams",
+			stream + 1,
+			audio_streams
+		)));
+	}
+
 	pipeline
 		.set_state(gstreamer::State::Null)
 		.context("Unable to set the pipeline to the `Null` state")?;
@@ -618,22 +2114,194 @@ async fn transcode_gstreamer(
 	Ok(())
 }
 
-async fn rm_file_on_err<F, T>(path: &Path, f: F) -> Result<T>
+/// Seeks the prerolled `pipeline` to the start of `segment`, stopping at its end
+fn seek_segment(pipeline: &gstreamer::Pipeline, segment: &segment::Segment) -> Result<()> {
+	let end = segment
+		.end
+		.map(|end| gstreamer::ClockTime::from_nseconds(end.as_nanos() as u64));
+	pipeline
+		.seek(
+			1.0,
+			gstreamer::SeekFlags::FLUSH | gstreamer::SeekFlags::ACCURATE,
+			gstreamer::SeekType::Set,
+			gstreamer::ClockTime::from_nseconds(segment.start.as_nanos() as u64),
+			if end.is_some() {
+				gstreamer::SeekType::Set
+			} else {
+				gstreamer::SeekType::None
+			},
+			end,
+		)
+		.with_context(|| format!("Unable to seek to track {}", segment.number))?;
+	Ok(())
+}
+
+/// Makes `decodebin` expose video streams without decoding them, as they are never converted
+fn skip_video_decoding(decodebin: &Element) {
+	decodebin.connect("autoplug-select", false, |values| {
+		let select_result = glib::Type::from_name("GstAutoplugSelectResult")
+			.and_then(glib::EnumClass::with_type)
+			.expect("decodebin has no GstAutoplugSelectResult type");
+		let is_video_decoder = values[3]
+			.get::<gstreamer::ElementFactory>()
+			.is_ok_and(|factory| {
+				factory.has_type(
+					gstreamer::ElementFactoryType::DECODER
+						| gstreamer::ElementFactoryType::MEDIA_VIDEO,
+				)
+			});
+
+		// `GST_AUTOPLUG_SELECT_EXPOSE` or `GST_AUTOPLUG_SELECT_TRY`
+		select_result.to_value(if is_video_decoder { 1 } else { 0 })
+	});
+}
+
+/// Links `pad` to a `fakesink`, dropping the data of a stream that isn't converted
+fn discard_pad(pipeline: &gstreamer::Pipeline, pad: &gstreamer::Pad) -> Result<()> {
+	let sink: Element = gmake("fakesink", &[])?;
+	pipeline.add(&sink)?;
+	sink.sync_state_with_parent()?;
+
+	let sink_pad = sink.static_pad("sink").expect("fakesink has no sinkpad");
+	pad.link(&sink_pad)?;
+
+	Ok(())
+}
+
+/// The error of an error message, either the one passed to `element_error!` or the gstreamer one
+fn message_error(msg: &gstreamer::Message, err: &gstreamer::message::Error) -> Error {
+	err.details()
+		.and_then(|details| {
+			if details.name() != "error-details" {
+				return None;
+			}
+
+			let err = details
+				.get::<&GBoxErrorWrapper>("error")
+				.unwrap()
+				.clone()
+				.into();
+			Some(err)
+		})
+		.unwrap_or_else(|| {
+			GErrorMessage {
+				src: msg
+					.src()
+					.map(|s| String::from(s.path_string()))
+					.unwrap_or_else(|| String::from("None")),
+				error: err.error().to_string(),
+				debug: err.debug().map(|gstring| gstring.into()),
+				source: err.error(),
+			}
+			.into()
+		})
+}
+
+/// Encoder and muxer elements for `transcode`
+fn encoder_elems(transcode: &Transcode) -> Result<Vec<Element>> {
+	let elems = match transcode {
+		Transcode::Opus {
+			bitrate,
+			bitrate_type,
+		} => {
+			let encoder: Element = gmake(
+				"opusenc",
+				&[
+					(
+						"bitrate",
+						&i32::from(*bitrate)
+							.checked_mul(1_000)
+							.context("Bitrate overflowed")?,
+					),
+					(
+						"bitrate-type",
+						match bitrate_type {
+							config::BitrateType::Vbr => &"1",
+							config::BitrateType::Cbr => &"0",
+						},
+					),
+				],
+			)?;
+
+			vec![encoder, gmake("oggmux", &[])?]
+		}
+
+		Transcode::Flac { compression } => {
+			let encoder: Element = gmake("flacenc", &[("quality", &compression.to_string())])?;
+			vec![encoder]
+		}
+
+		Transcode::Mp3 {
+			bitrate,
+			bitrate_type,
+		} => {
+			let encoder: Element = gmake(
+				"lamemp3enc",
+				&[
+					// target: "1" = "bitrate"
+					("target", &"1"),
+					("bitrate", &i32::from(*bitrate)),
+					(
+						"cbr",
+						match bitrate_type {
+							config::BitrateType::Vbr => &false,
+							config::BitrateType::Cbr => &true,
+						},
+					),
+				],
+			)?;
+
+			vec![encoder, gmake("id3v2mux", &[])?]
+		}
+
+		Transcode::Copy { .. } => {
+			// already handled outside of gstreamer
+			unreachable!();
+		}
+	};
+	Ok(elems)
+}
+
+/// Sets the pipeline to the `Null` state when dropped, e.g. when the transcode gets cancelled
+struct PipelineGuard(gstreamer::Pipeline);
+
+impl Drop for PipelineGuard {
+	fn drop(&mut self) {
+		let _ = self.0.set_state(gstreamer::State::Null);
+	}
+}
+
+async fn rm_file_on_err<F, T>(paths: &[&Path], f: F) -> Result<T>
 where
 	F: Future<Output = Result<T>>,
 {
-	match f.await {
-		Err(err) => match fs::remove_file(path).await {
-			Ok(()) => Err(err),
-			Err(fs_err) if fs_err.kind() == std::io::ErrorKind::NotFound => Err(err),
+	let mut err = match f.await {
+		Err(err) => err,
+		res @ Ok(..) => return res,
+	};
+
+	for path in paths {
+		match fs::remove_file(path).await {
+			Ok(()) => {}
+			Err(fs_err) if fs_err.kind() == std::io::ErrorKind::NotFound => {}
 			Err(fs_err) => {
-				let err = err
+				err = err
 					.context(fs_err)
 					.context(format!("Removing file {} failed", path.display()));
-				Err(err)
 			}
-		},
-		res @ Ok(..) => res,
+		}
+	}
+
+	Err(err)
+}
+
+/// Decodes text files like CUE sheets or playlists, which are often not UTF-8 but encoded with the
+/// code page of the machine that wrote them, latin-1 is the most common one
+fn decode_text(bytes: &[u8]) -> String {
+	let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+	match std::str::from_utf8(bytes) {
+		Ok(text) => text.to_string(),
+		Err(_) => bytes.iter().map(|b| char::from(*b)).collect(),
 	}
 }
 
@@ -659,3 +2327,106 @@ fn path_to_bytes(path: &Path) -> Cow<'_, [u8]> {
 		Cow::Owned(buf)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use config::CopyMethod;
+	use segment::{SegmentKey, SegmentSource};
+
+	fn track(source: SegmentSource, number: u32, start: u64, end: Option<u64>) -> SegmentKey {
+		SegmentKey {
+			source,
+			number,
+			start: Duration::from_secs(start),
+			end: end.map(Duration::from_secs),
+		}
+	}
+
+	#[test]
+	fn group_targets_split_differently() {
+		// (target, key), `None` for copies
+		let cue_1 = track(SegmentSource::Cue, 1, 0, Some(180));
+		let cue_2 = track(SegmentSource::Cue, 2, 180, None);
+		let chapter_1 = track(SegmentSource::Chapters, 1, 0, Some(300));
+		let outputs = [
+			(0, Some(Some(cue_1))),
+			(0, Some(Some(cue_2))),
+			(1, Some(Some(chapter_1))),
+			(2, Some(Some(cue_1))),
+			(3, None),
+			(4, Some(None)),
+			(5, Some(None)),
+		];
+
+		let groups: Vec<Vec<usize>> = group_by_key(&outputs, |(_, key)| *key)
+			.into_iter()
+			.map(|group| group.iter().map(|(target, _)| *target).collect())
+			.collect();
+		assert_eq!(groups, [vec![0, 2], vec![0], vec![1], vec![3], vec![4, 5]]);
+	}
+
+	#[test]
+	fn same_track_number_of_different_sources() {
+		let cue = track(SegmentSource::Cue, 1, 0, Some(180));
+		let chapter = track(SegmentSource::Chapters, 1, 0, Some(180));
+		let outputs = [cue, chapter];
+		assert_eq!(group_by_key(&outputs, |key| Some(*key)).len(), 2);
+	}
+
+	fn conversion(rel_from_path: &str, outputs: &[(usize, Transcode)]) -> ConversionArgs {
+		ConversionArgs {
+			rel_from_path: PathBuf::from(rel_from_path),
+			size: 0,
+			outputs: outputs
+				.iter()
+				.map(|(target, transcode)| Output {
+					target: *target,
+					transcode: transcode.clone(),
+					rule: None,
+					segment: None,
+					stream: 0,
+					stream_id: None,
+					split_streams: false,
+					video_audio: false,
+				})
+				.collect(),
+			is_lossy: None,
+		}
+	}
+
+	#[test]
+	fn name_collisions() {
+		let target = |to: &str, fat_safe_names| Target {
+			name: None,
+			to: PathBuf::from(to),
+			matches: Vec::new(),
+			fat_safe_names,
+		};
+		let targets = [target("/fat", true), target("/plain", false)];
+		let opus = Transcode::default();
+		let copy = Transcode::Copy {
+			method: CopyMethod::Copy,
+		};
+		let mut paths = HashMap::new();
+		let mut find = |rel_from_path, outputs: &[(usize, Transcode)]| {
+			find_name_collision(&targets, &mut paths, &conversion(rel_from_path, outputs))
+				.map(|collision| collision.to)
+		};
+
+		assert!(find("a?.flac", &[(0, opus.clone()), (1, opus.clone())]).is_none());
+		// copies keep their extension
+		assert!(find("a_.jpg", &[(0, copy.clone())]).is_none());
+		// only the outputs the file gets are compared
+		assert!(find("a*.flac", &[(1, opus.clone())]).is_none());
+		assert_eq!(
+			find("a*.wav", &[(1, opus.clone()), (0, opus.clone())]),
+			Some(PathBuf::from("/fat/a_.opus"))
+		);
+		assert!(find("b/a|.jpg", &[(0, copy.clone())]).is_none());
+		assert_eq!(
+			find("b/a?.jpg", &[(0, copy)]),
+			Some(PathBuf::from("/fat/b/a_.jpg"))
+		);
+	}
+}
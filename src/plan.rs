@@ -0,0 +1,86 @@
+use crate::{
+	Job, config::Config, get_jobs, get_output_path, init_gstreamer, playlist, probe, summary,
+};
+use anyhow::Result;
+use std::{
+	io::{self, Write},
+	process::ExitCode,
+};
+
+/// Prints the files that would be converted and the rules applying to them, without converting
+/// anything
+pub fn print(config: &Config) -> Result<ExitCode> {
+	let mut out = io::stdout().lock();
+	let mut has_errors = false;
+
+	// needed for probing files
+	init_gstreamer()?;
+
+	for job in get_jobs(config) {
+		let args = match job {
+			Ok(Job::Convert(args)) => args,
+			Ok(Job::Playlist { rel_from_path }) => {
+				for target in &config.targets {
+					let to_path = playlist::get_to_path(target, &rel_from_path);
+					let to_path = to_path.strip_prefix(&target.to).unwrap_or(&to_path);
+
+					write!(out, "{} -> ", rel_from_path.display())?;
+					if let Some(name) = &target.name {
+						write!(out, "{}: ", name)?;
+					}
+					writeln!(out, "{} [playlist]", to_path.display())?;
+				}
+				continue;
+			}
+			Ok(_) => continue,
+			Err(err) => {
+				has_errors = true;
+				eprintln!("Error: {:?}", err);
+				continue;
+			}
+		};
+
+		// files are only probed here if that wasn't needed to find their outputs, files that can't
+		// be probed aren't flagged, their conversion most likely fails anyway
+		let is_lossy_source = args
+			.outputs
+			.iter()
+			.any(|output| output.transcode.bitrate().is_some())
+			&& args.is_lossy.unwrap_or_else(|| {
+				probe::probe(&config.from.join(&args.rel_from_path))
+					.is_ok_and(|probe| !probe.lossless)
+			});
+
+		for output in &args.outputs {
+			let target = &config.targets[output.target];
+			let to_path = get_output_path(&config.targets, &args.rel_from_path, output);
+			let to_path = to_path.strip_prefix(&target.to).unwrap_or(&to_path);
+
+			write!(out, "{} -> ", args.rel_from_path.display())?;
+			if let Some(name) = &target.name {
+				write!(out, "{}: ", name)?;
+			}
+			write!(out, "{} [{}]", to_path.display(), output.transcode)?;
+			if output.video_audio {
+				write!(out, " (audio from video)")?;
+			}
+			if output.stream != 0 && !output.split_streams {
+				write!(out, " (audio stream {})", output.stream + 1)?;
+			}
+			if is_lossy_source && output.transcode.bitrate().is_some() {
+				write!(out, " (lossy to lossy)")?;
+			}
+			if let Some(rule) = &output.rule {
+				let rule = rule.strip_prefix(&config.from).unwrap_or(rule);
+				write!(out, " (rule from {})", rule.display())?;
+			}
+			writeln!(out)?;
+		}
+	}
+
+	if has_errors {
+		Ok(ExitCode::from(summary::EXIT_CODE_FAILED))
+	} else {
+		Ok(ExitCode::SUCCESS)
+	}
+}
@@ -0,0 +1,301 @@
+use crate::{
+	config::{Config, PathSeparator, PlaylistConfig, PlaylistEncoding, PlaylistPaths, Target},
+	decode_text, dir_config, get_output_path, get_outputs, get_rel_to_path,
+	probe::LazyProbe,
+	tmp_file,
+};
+use anyhow::{Context, Result};
+use std::{
+	borrow::Cow,
+	collections::HashSet,
+	path::{Component, Path, PathBuf},
+};
+
+/// Whether `path` is a ".m3u" or ".m3u8" playlist
+pub fn is_playlist(path: &Path) -> bool {
+	path.extension()
+		.is_some_and(|ext| ext.eq_ignore_ascii_case("m3u") || ext.eq_ignore_ascii_case("m3u8"))
+}
+
+/// Path of the playlist `rel_from_path` in the "to" dir of `target`
+pub fn get_to_path(target: &Target, rel_from_path: &Path) -> PathBuf {
+	target.to.join(get_rel_to_path(target, rel_from_path))
+}
+
+/// Writes the playlist `rel_from_path` into the "to" dir of every target, with its entries
+/// pointing to their outputs. Entries without an output are left out, a warning is returned for
+/// each of them and for the entries in `failed`, the files whose conversion failed. Playlists
+/// that didn't change aren't written again.
+pub fn convert(
+	config: &Config,
+	playlist_config: &PlaylistConfig,
+	dir_configs: &mut dir_config::DirConfigs,
+	rel_from_path: &Path,
+	failed: &HashSet<PathBuf>,
+) -> Result<Vec<String>> {
+	let from_path = config.from.join(rel_from_path);
+	let bytes = std::fs::read(&from_path)
+		.with_context(|| format!("Unable to read playlist {}", from_path.display()))?;
+	let text = decode_text(&bytes);
+	let from_dir = from_path.parent().unwrap_or(&config.from);
+
+	let mut warnings = Vec::new();
+	// the lines of the playlist for every target
+	let mut playlists: Vec<Vec<Cow<str>>> = vec![Vec::new(); config.targets.len()];
+	// "#EXTINF" lines describe the entry following them, they are left out together with it
+	let mut entry_info = Vec::new();
+
+	for (line_number, line) in text.lines().enumerate() {
+		let entry = line.trim();
+		if entry.starts_with("#EXTINF") {
+			entry_info.push(line);
+			continue;
+		}
+
+		// comments, directives and URLs are kept as they are
+		if entry.is_empty() || entry.starts_with('#') || entry.contains("://") {
+			for lines in &mut playlists {
+				lines.extend(entry_info.iter().map(|line| Cow::Borrowed(*line)));
+				lines.push(Cow::Borrowed(line));
+			}
+			entry_info.clear();
+			continue;
+		}
+
+		let mut warn = |msg: &str| {
+			warnings.push(format!(
+				"{}, line {}: \"{}\" {}",
+				rel_from_path.display(),
+				line_number + 1,
+				entry,
+				msg
+			));
+		};
+
+		let entry_path = resolve(from_dir, entry);
+		// whether the reason for the entry not having any outputs was already given
+		let mut is_warned = true;
+		let outputs = if !entry_path.starts_with(&config.from) {
+			warn("is outside of the \"from\" dir");
+			Vec::new()
+		} else if !entry_path.is_file() {
+			warn("doesn't exist");
+			Vec::new()
+		} else {
+			match get_outputs(config, dir_configs, &mut LazyProbe::new(&entry_path)) {
+				Ok(outputs) => {
+					is_warned = false;
+					outputs
+				}
+				Err(err) => {
+					warn(&format!("can't be converted: {:#}", err));
+					Vec::new()
+				}
+			}
+		};
+		let rel_entry_path = entry_path.strip_prefix(&config.from).unwrap_or(&entry_path);
+		if failed.contains(rel_entry_path) {
+			warn("failed to convert");
+			is_warned = true;
+		}
+
+		for (target_idx, (target, lines)) in config.targets.iter().zip(&mut playlists).enumerate() {
+			// outputs that don't exist, like the ones of failed conversions, are left out
+			let mut target_outputs = outputs
+				.iter()
+				.filter(|output| output.target == target_idx)
+				.map(|output| get_output_path(&config.targets, rel_entry_path, output))
+				.filter(|output_path| output_path.is_file())
+				.peekable();
+			if target_outputs.peek().is_none() {
+				if !is_warned {
+					match &target.name {
+						Some(name) => warn(&format!("isn't converted for target \"{}\"", name)),
+						None => warn("isn't converted"),
+					}
+				}
+				continue;
+			}
+
+			lines.extend(entry_info.iter().map(|line| Cow::Borrowed(*line)));
+			for output_path in target_outputs {
+				let entry = entry_string(playlist_config, target, rel_from_path, &output_path)?;
+				lines.push(Cow::Owned(entry));
+			}
+		}
+		entry_info.clear();
+	}
+
+	let line_ending = if text.contains("\r\n") { "\r\n" } else { "\n" };
+	let is_utf8 = playlist_config.encoding == PlaylistEncoding::Utf8
+		|| rel_from_path
+			.extension()
+			.is_some_and(|ext| ext.eq_ignore_ascii_case("m3u8"));
+
+	let mut has_unrepresentable_chars = false;
+	for (target, lines) in config.targets.iter().zip(playlists) {
+		let mut text = lines.join(line_ending);
+		text.push_str(line_ending);
+
+		let bytes = if is_utf8 {
+			text.into_bytes()
+		} else {
+			text.chars()
+				.map(|c| {
+					u8::try_from(c).unwrap_or_else(|_| {
+						has_unrepresentable_chars = true;
+						b'?'
+					})
+				})
+				.collect()
+		};
+
+		let to_path = get_to_path(target, rel_from_path);
+		if std::fs::read(&to_path).is_ok_and(|old_bytes| old_bytes == bytes) {
+			continue;
+		}
+		write(&to_path, &bytes)?;
+	}
+
+	if has_unrepresentable_chars {
+		warnings.push(format!(
+			"{}: characters that can't be represented in latin-1 were replaced with \"?\"",
+			rel_from_path.display()
+		));
+	}
+
+	Ok(warnings)
+}
+
+/// The path of `entry` in a playlist in `dir`, without "." and ".." components
+fn resolve(dir: &Path, entry: &str) -> PathBuf {
+	// playlists written on windows use backslashes
+	let entry = if cfg!(windows) {
+		Cow::Borrowed(entry)
+	} else {
+		Cow::Owned(entry.replace('\\', "/"))
+	};
+
+	let mut path = PathBuf::new();
+	for c in dir.join(entry.as_ref()).components() {
+		match c {
+			// a leading "." is kept, like the one of the "from" dir
+			Component::CurDir if !path.as_os_str().is_empty() => {}
+			Component::ParentDir if path.file_name().is_some() => {
+				path.pop();
+			}
+			c => path.push(c),
+		}
+	}
+	path
+}
+
+/// The entry pointing to `output_path` in the playlist `rel_from_path` of `target`
+fn entry_string(
+	playlist_config: &PlaylistConfig,
+	target: &Target,
+	rel_from_path: &Path,
+	output_path: &Path,
+) -> Result<String> {
+	let path = match playlist_config.paths {
+		PlaylistPaths::Absolute => std::path::absolute(output_path)
+			.with_context(|| format!("Unable to get absolute path of {}", output_path.display()))?,
+		PlaylistPaths::Relative => {
+			let playlist_dir = get_rel_to_path(target, rel_from_path)
+				.parent()
+				.map(Path::to_path_buf)
+				.unwrap_or_default();
+			let rel_output_path = output_path.strip_prefix(&target.to).unwrap_or(output_path);
+			relative_path(&playlist_dir, rel_output_path)
+		}
+	};
+
+	let path = path.to_string_lossy();
+	let path = match playlist_config.separator {
+		PathSeparator::Native => path.into_owned(),
+		PathSeparator::Slash => path.replace('\\', "/"),
+		PathSeparator::Backslash => path.replace('/', "\\"),
+	};
+	Ok(path)
+}
+
+/// `path` relative to `dir`, both relative to the same dir
+fn relative_path(dir: &Path, path: &Path) -> PathBuf {
+	let common = dir
+		.components()
+		.zip(path.components())
+		.take_while(|(a, b)| a == b)
+		.count();
+
+	dir.components()
+		.skip(common)
+		.map(|_| Component::ParentDir)
+		.chain(path.components().skip(common))
+		.collect()
+}
+
+/// Writes `bytes` to `to_path`, through a temporary file
+fn write(to_path: &Path, bytes: &[u8]) -> Result<()> {
+	if let Some(parent) = to_path.parent() {
+		std::fs::create_dir_all(parent)
+			.with_context(|| format!("Could not create dir {}", parent.display()))?;
+	}
+
+	let tmp_file = tmp_file::TmpFile::new(to_path);
+	std::fs::write(tmp_file.path(), bytes)
+		.with_context(|| format!("Could not write {}", tmp_file.path().display()))?;
+	std::fs::rename(tmp_file.path(), to_path).with_context(|| {
+		format!(
+			"Could not rename temporary file {} to {}",
+			tmp_file.path().display(),
+			to_path.display()
+		)
+	})?;
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn resolve_entries() {
+		let dir = Path::new("./music/Playlists");
+		assert_eq!(
+			resolve(dir, "../Artist/01.flac"),
+			Path::new("./music/Artist/01.flac")
+		);
+		assert_eq!(
+			resolve(dir, "./01.flac"),
+			Path::new("./music/Playlists/01.flac")
+		);
+		assert_eq!(resolve(dir, "../../../01.flac"), Path::new("./../01.flac"));
+		assert_eq!(resolve(dir, "/srv/01.flac"), Path::new("/srv/01.flac"));
+		#[cfg(not(windows))]
+		assert_eq!(
+			resolve(dir, "..\\Artist\\01.flac"),
+			Path::new("./music/Artist/01.flac")
+		);
+	}
+
+	#[test]
+	fn relative_paths() {
+		assert_eq!(
+			relative_path(Path::new("Playlists"), Path::new("Artist/01.opus")),
+			Path::new("../Artist/01.opus")
+		);
+		assert_eq!(
+			relative_path(Path::new("Artist"), Path::new("Artist/Album/01.opus")),
+			Path::new("Album/01.opus")
+		);
+		assert_eq!(
+			relative_path(Path::new(""), Path::new("Artist/01.opus")),
+			Path::new("Artist/01.opus")
+		);
+		assert_eq!(
+			relative_path(Path::new("a/b"), Path::new("a/c/01.opus")),
+			Path::new("../c/01.opus")
+		);
+	}
+}
@@ -0,0 +1,273 @@
+use crate::config::{SourceCondition, TagCondition, ValueRange};
+use anyhow::{Context, Error, Result};
+use gstreamer::{Caps, ClockTime, CoreError, ResourceError, Tag, TagList, TagMergeMode};
+use gstreamer_pbutils::{Discoverer, DiscovererResult, prelude::*};
+use std::{path::Path, time::Duration};
+
+/// Longest time it may take to read the stream properties of a file
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Properties of the first audio stream of a file, and the audio streams it has
+#[derive(Debug, Clone)]
+pub struct Probe {
+	/// names like "mp3", "aac", "flac", "opus", "vorbis" or "pcm"
+	pub codec: String,
+	pub lossless: bool,
+	pub sample_rate: Option<u32>,
+	/// only known for lossless codecs
+	pub bit_depth: Option<u32>,
+	pub channels: Option<u32>,
+	/// in bit/s
+	pub bitrate: Option<u64>,
+	pub duration: Option<Duration>,
+	/// global tags of the file and the tags of the audio stream
+	pub tags: TagList,
+	/// table of contents, like the chapters of an audiobook
+	pub toc: Option<gstreamer::Toc>,
+	/// all audio streams of the file, in the order gstreamer reports them
+	pub audio_streams: Vec<AudioStream>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AudioStream {
+	/// identifies the stream, the same in every pipeline reading the file
+	pub stream_id: Option<String>,
+	/// ISO 639 language code, like "en"
+	pub language: Option<String>,
+}
+
+impl Probe {
+	/// Whether all conditions are satisfied, unknown properties never satisfy a condition
+	pub fn satisfies(&self, condition: &SourceCondition) -> bool {
+		fn in_range<T: PartialOrd + Copy>(range: &Option<ValueRange<T>>, value: Option<T>) -> bool {
+			match (range, value) {
+				(None, _) => true,
+				(Some(range), Some(value)) => range.contains(value),
+				(Some(_), None) => false,
+			}
+		}
+
+		(condition.codec.is_empty()
+			|| condition
+				.codec
+				.iter()
+				.any(|codec| codec.eq_ignore_ascii_case(&self.codec)))
+			&& condition
+				.lossless
+				.is_none_or(|lossless| lossless == self.lossless)
+			&& in_range(&condition.sample_rate, self.sample_rate)
+			&& in_range(&condition.bit_depth, self.bit_depth)
+			&& in_range(&condition.channels, self.channels)
+			&& in_range(
+				&condition.bitrate,
+				self.bitrate.map(|bitrate| (bitrate / 1000) as u32),
+			) && in_range(
+			&condition.duration,
+			self.duration.map(|duration| duration.as_secs_f64()),
+		)
+	}
+
+	/// Values of the tag `name`, either a gstreamer tag name like "genre" or "album-artist", or
+	/// the key of a custom tag like "COMPILATION" in vorbis comments, ignoring case
+	pub fn tag_values(&self, name: &str) -> Vec<String> {
+		let values_of = |tag_name: &str| {
+			(0..self.tags.size_by_name(tag_name))
+				.filter_map(|idx| self.tags.index_generic(tag_name, idx))
+				.filter_map(|value| {
+					value
+						.transform::<String>()
+						.ok()
+						.and_then(|value| value.get::<String>().ok())
+						.or_else(|| value.serialize().ok().map(String::from))
+				})
+				.collect::<Vec<_>>()
+		};
+
+		let mut values = values_of(&name.to_ascii_lowercase());
+
+		// custom tags end up as "key=value" extended comments
+		values.extend(
+			values_of(gstreamer::tags::ExtendedComment::TAG_NAME.as_str())
+				.into_iter()
+				.filter_map(|comment| {
+					let (key, value) = comment.split_once('=')?;
+					key.eq_ignore_ascii_case(name).then(|| value.to_string())
+				}),
+		);
+
+		values
+	}
+
+	pub fn satisfies_tag(&self, name: &str, condition: &TagCondition) -> bool {
+		let values = self.tag_values(name);
+		let is_set = !values.is_empty();
+
+		condition.exists.is_none_or(|exists| exists == is_set)
+			&& condition.equals.as_ref().is_none_or(|equals| {
+				values
+					.iter()
+					.any(|value| value.trim().eq_ignore_ascii_case(equals))
+			}) && condition
+			.regex
+			.as_ref()
+			.is_none_or(|regex| values.iter().any(|value| regex.is_match(value.as_bytes())))
+	}
+}
+
+/// Reads the properties of the first audio stream of `path` with gstreamer's discoverer, which
+/// only parses the file without decoding it
+pub fn probe(path: &Path) -> Result<Probe> {
+	let abs_path = std::path::absolute(path)
+		.with_context(|| format!("Unable to get absolute path of {}", path.display()))?;
+	let uri = glib::filename_to_uri(&abs_path, None)
+		.with_context(|| format!("Unable to get URI of {}", abs_path.display()))?;
+
+	let discoverer = Discoverer::new(ClockTime::from_nseconds(PROBE_TIMEOUT.as_nanos() as u64))
+		.context("Could not create discoverer")?;
+	let info = discoverer
+		.discover_uri(&uri)
+		.map_err(|err| match err.kind::<ResourceError>() {
+			Some(_) => Error::new(err).context("Unable to read the file"),
+			None if err.matches(CoreError::MissingPlugin) => {
+				Error::new(err).context("No decoder found")
+			}
+			None => Error::new(err),
+		})?;
+	if info.result() == DiscovererResult::Timeout {
+		return Err(Error::msg(format!(
+			"Timed out after {} seconds",
+			PROBE_TIMEOUT.as_secs()
+		)));
+	}
+
+	let streams = info.audio_streams();
+	let audio = streams
+		.first()
+		.ok_or_else(|| Error::msg("No audio stream found"))?;
+
+	// global tags, like the ones of the container, merged with the tags of the audio stream
+	let mut tags = audio.tags().unwrap_or_else(TagList::new);
+	if let Some(global_tags) = info.tags() {
+		tags.make_mut().insert(&global_tags, TagMergeMode::Append);
+	}
+
+	let audio_streams = streams
+		.iter()
+		.map(|stream| AudioStream {
+			stream_id: stream.stream_id().map(String::from),
+			language: stream.language().map(String::from),
+		})
+		.collect();
+
+	let toc = info.toc().or_else(|| audio.toc());
+
+	let (codec, lossless) = match audio.caps() {
+		Some(caps) => codec_name(&caps),
+		None => (String::from("unknown"), false),
+	};
+
+	let duration = info
+		.duration()
+		.map(|duration| Duration::from_nanos(duration.nseconds()));
+
+	// 0 if unknown
+	let known = |value: u32| Some(value).filter(|value| *value > 0);
+
+	let bitrate = known(audio.bitrate())
+		.or_else(|| {
+			tags.get::<gstreamer::tags::Bitrate>()
+				.map(|bitrate| bitrate.get())
+		})
+		.or_else(|| {
+			tags.get::<gstreamer::tags::NominalBitrate>()
+				.map(|bitrate| bitrate.get())
+		})
+		.map(u64::from)
+		.or_else(|| {
+			// estimated from the file size, which includes the container overhead and cover art
+			let size = path.metadata().ok()?.len();
+			let secs = duration?.as_secs_f64();
+			(secs > 0.0).then(|| (size as f64 * 8.0 / secs) as u64)
+		});
+
+	Ok(Probe {
+		codec,
+		lossless,
+		sample_rate: known(audio.sample_rate()),
+		bit_depth: known(audio.depth()).filter(|_| lossless),
+		channels: known(audio.channels()),
+		bitrate,
+		duration,
+		tags,
+		toc,
+		audio_streams,
+	})
+}
+
+/// Short codec name and whether the codec is lossless
+fn codec_name(caps: &Caps) -> (String, bool) {
+	let s = match caps.structure(0) {
+		Some(s) => s,
+		None => return (String::from("unknown"), false),
+	};
+
+	match s.name().as_str() {
+		"audio/mpeg" => {
+			let version = s.get::<i32>("mpegversion").unwrap_or(1);
+			let layer = s.get::<i32>("layer").unwrap_or(3);
+			match (version, layer) {
+				(1, 1) => (String::from("mp1"), false),
+				(1, 2) => (String::from("mp2"), false),
+				(1, _) => (String::from("mp3"), false),
+				_ => (String::from("aac"), false),
+			}
+		}
+		"audio/x-flac" => (String::from("flac"), true),
+		"audio/x-alac" => (String::from("alac"), true),
+		"audio/x-wavpack" => (String::from("wavpack"), true),
+		"audio/x-ffmpeg-parsed-ape" | "audio/x-ape" => (String::from("ape"), true),
+		"audio/x-tta" => (String::from("tta"), true),
+		// raw audio, the caps of the container are reported for wav, aiff and w64 files
+		"audio/x-raw" | "audio/x-wav" | "audio/x-aiff" | "audio/x-w64" => {
+			(String::from("pcm"), true)
+		}
+		name => {
+			let name = name
+				.strip_prefix("audio/x-")
+				.or_else(|| name.strip_prefix("audio/"))
+				.unwrap_or(name);
+			(name.to_string(), false)
+		}
+	}
+}
+
+/// Probes the file the first time its properties are needed
+#[derive(Debug)]
+pub struct LazyProbe<'a> {
+	path: &'a Path,
+	probe: Option<Probe>,
+}
+
+impl<'a> LazyProbe<'a> {
+	pub fn new(path: &'a Path) -> Self {
+		LazyProbe { path, probe: None }
+	}
+
+	pub fn path(&self) -> &'a Path {
+		self.path
+	}
+
+	/// The properties of the file, if it was probed already
+	pub fn probed(&self) -> Option<&Probe> {
+		self.probe.as_ref()
+	}
+
+	pub fn get(&mut self) -> Result<&Probe> {
+		if self.probe.is_none() {
+			let probe = probe(self.path)
+				.with_context(|| format!("Failed probing {}", self.path.display()))?;
+			self.probe = Some(probe);
+		}
+		Ok(self.probe.as_ref().unwrap())
+	}
+}
@@ -0,0 +1,96 @@
+use crate::GErrorMessage;
+use anyhow::Error;
+use gstreamer::ResourceError;
+use std::{io, time::Duration};
+
+/// Delay before the first retry, doubled for every further one
+const BASE_DELAY: Duration = Duration::from_secs(2);
+
+/// Longest delay between two retries
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Whether retrying might succeed, like for I/O errors of network filesystems or a full disk.
+///
+/// Errors like missing gstreamer plugins or corrupt files fail the same way on every try.
+pub fn is_transient(err: &Error) -> bool {
+	err.chain().any(|err| {
+		if let Some(err) = err.downcast_ref::<io::Error>() {
+			return is_transient_io(err);
+		}
+
+		if let Some(msg) = err.downcast_ref::<GErrorMessage>() {
+			return matches!(
+				msg.source.kind::<ResourceError>(),
+				Some(
+					ResourceError::Busy
+						| ResourceError::Read
+						| ResourceError::Write
+						| ResourceError::Seek
+						| ResourceError::Sync
+						| ResourceError::Close
+						| ResourceError::NoSpaceLeft
+				)
+			);
+		}
+
+		false
+	})
+}
+
+fn is_transient_io(err: &io::Error) -> bool {
+	use io::ErrorKind::*;
+
+	matches!(
+		err.kind(),
+		Interrupted
+			| TimedOut
+			| WouldBlock
+			| ResourceBusy
+			| StorageFull
+			| QuotaExceeded
+			| StaleNetworkFileHandle
+			| ConnectionReset
+			| ConnectionAborted
+			| NotConnected
+	) || err.raw_os_error() == Some(libc::EIO)
+}
+
+/// Delay before the given retry, starting at 1
+pub fn delay(retry: u32) -> Duration {
+	BASE_DELAY
+		.checked_mul(1 << (retry - 1).min(16))
+		.unwrap_or(MAX_DELAY)
+		.min(MAX_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use anyhow::Context;
+
+	#[test]
+	fn transient_errors() {
+		let io_err = |kind| Error::new(io::Error::from(kind));
+		assert!(is_transient(&io_err(io::ErrorKind::TimedOut)));
+		assert!(is_transient(&io_err(io::ErrorKind::StorageFull)));
+		assert!(is_transient(&Error::new(io::Error::from_raw_os_error(
+			libc::EIO
+		))));
+		assert!(!is_transient(&io_err(io::ErrorKind::NotFound)));
+		assert!(!is_transient(&io_err(io::ErrorKind::PermissionDenied)));
+		assert!(!is_transient(&Error::msg("no audio stream")));
+
+		// the cause decides, not the context added to it
+		let err = Err::<(), _>(io::Error::from(io::ErrorKind::StaleNetworkFileHandle))
+			.context("Could not copy file")
+			.unwrap_err();
+		assert!(is_transient(&err));
+	}
+
+	#[test]
+	fn delays() {
+		let delays: Vec<_> = (1..=7).map(|retry| delay(retry).as_secs()).collect();
+		assert_eq!(delays, [2, 4, 8, 16, 32, 60, 60]);
+		assert_eq!(delay(100), MAX_DELAY);
+	}
+}
@@ -0,0 +1,107 @@
+use crate::{
+	chapters,
+	config::{SplitCue, Target},
+	cue, fat_safe_name,
+	probe::LazyProbe,
+};
+use anyhow::{Context, Result};
+use gstreamer::TagList;
+use std::time::Duration;
+
+/// Where the segments of a file come from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SegmentSource {
+	Cue,
+	Chapters,
+}
+
+/// Part of a file that gets converted into an output of its own, like a track of a CUE sheet
+#[derive(Clone, Debug)]
+pub struct Segment {
+	pub source: SegmentSource,
+	/// starting at 1, used for the file name
+	pub number: u32,
+	pub title: Option<String>,
+	pub start: Duration,
+	/// `None` for the end of the file
+	pub end: Option<Duration>,
+	/// set on the output, replacing the tags of the file
+	pub tags: TagList,
+}
+
+/// Identifies a segment among the ones of all split sources of a file, outputs of the same
+/// segment can be converted in a single pipeline
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SegmentKey {
+	pub source: SegmentSource,
+	pub number: u32,
+	pub start: Duration,
+	pub end: Option<Duration>,
+}
+
+impl Segment {
+	pub fn key(&self) -> SegmentKey {
+		SegmentKey {
+			source: self.source,
+			number: self.number,
+			start: self.start,
+			end: self.end,
+		}
+	}
+
+	/// File name of the output without extension, like "01 - Title", with the characters `target`
+	/// doesn't allow replaced
+	pub fn file_stem(&self, target: &Target) -> String {
+		let stem = match &self.title {
+			Some(title) => {
+				let title: String = title
+					.chars()
+					.map(|c| match c {
+						'/' | '\\' | '\0' => '_',
+						c => c,
+					})
+					.collect();
+				format!("{:02} - {}", self.number, title.trim())
+			}
+			None => format!("{:02}", self.number),
+		};
+
+		if target.fat_safe_names {
+			fat_safe_name(&stem)
+		} else {
+			stem
+		}
+	}
+}
+
+/// The segments the file of `probe` gets split into, taken from its CUE sheet or, if
+/// `split_chapters` is set, its chapters, `None` if it isn't split
+pub fn find(
+	probe: &mut LazyProbe,
+	split_cue: SplitCue,
+	split_chapters: bool,
+) -> Result<Option<Vec<Segment>>> {
+	let path = probe.path();
+	let sheet = cue::find(probe, split_cue)
+		.with_context(|| format!("Unable to read CUE sheet for {}", path.display()))?;
+	if let Some(sheet) = sheet {
+		let file_name = path.file_name().unwrap_or_default();
+		return Ok(Some(sheet.segments(file_name)));
+	}
+
+	if split_chapters {
+		let chapters = probe
+			.get()
+			.with_context(|| format!("Unable to read chapters of {}", path.display()))?
+			.toc
+			.as_ref()
+			.map(|toc| chapters::from_toc(toc))
+			.unwrap_or_default();
+		// a single chapter isn't worth splitting
+		if chapters.len() > 1 {
+			return Ok(Some(chapters::segments(&chapters)));
+		}
+	}
+
+	Ok(None)
+}
@@ -0,0 +1,127 @@
+use std::{io::Write, path::PathBuf, time::Duration};
+
+/// Exit code used if at least one task failed, a part of the "from" dir couldn't be read or the
+/// outputs of files collide
+pub const EXIT_CODE_FAILED: u8 = 2;
+
+#[derive(Debug, Default)]
+pub struct Summary {
+	/// whether the planning was done and tasks were started
+	pub started: bool,
+	pub converted: usize,
+	pub copied: usize,
+	/// files that were converted already and didn't change since
+	pub up_to_date: usize,
+	/// files skipped by the user or not started because of a stop
+	pub skipped: usize,
+	pub failed: Vec<PathBuf>,
+	/// errors while walking the "from" dir
+	pub scan_errors: Vec<String>,
+	/// files whose outputs get the same path because of `fat_safe_names`
+	pub name_collisions: Vec<String>,
+	/// playlist entries that couldn't be rewritten
+	pub playlist_warnings: Vec<String>,
+	pub bytes_in: u64,
+	pub bytes_out: u64,
+}
+
+impl Summary {
+	pub fn print(&self, out: &mut dyn Write, elapsed: Duration) {
+		if !self.started {
+			return;
+		}
+
+		// there is nothing sensible left to do if writing the summary fails
+		let _ = self.write(out, elapsed);
+	}
+
+	fn write(&self, out: &mut dyn Write, elapsed: Duration) -> std::io::Result<()> {
+		writeln!(out)?;
+		writeln!(out, "Summary:")?;
+		writeln!(out, "  converted:  {}", self.converted)?;
+		writeln!(out, "  copied:     {}", self.copied)?;
+		writeln!(out, "  up to date: {}", self.up_to_date)?;
+		writeln!(out, "  skipped:    {}", self.skipped)?;
+		writeln!(out, "  failed:     {}", self.failed.len())?;
+		writeln!(out, "  unreadable: {}", self.scan_errors.len())?;
+		writeln!(out, "  bytes in:   {}", format_bytes(self.bytes_in))?;
+		writeln!(out, "  bytes out:  {}", format_bytes(self.bytes_out))?;
+		writeln!(out, "  elapsed:    {}", format_duration(elapsed))?;
+
+		if !self.failed.is_empty() {
+			writeln!(out)?;
+			writeln!(out, "Failed files:")?;
+			for path in &self.failed {
+				writeln!(out, "  {}", path.display())?;
+			}
+		}
+
+		if !self.scan_errors.is_empty() {
+			writeln!(out)?;
+			writeln!(out, "Unreadable paths:")?;
+			for err in &self.scan_errors {
+				writeln!(out, "  {}", err)?;
+			}
+		}
+
+		if !self.name_collisions.is_empty() {
+			writeln!(out)?;
+			writeln!(out, "Name collisions:")?;
+			for collision in &self.name_collisions {
+				writeln!(out, "  {}", collision)?;
+			}
+		}
+
+		if !self.playlist_warnings.is_empty() {
+			writeln!(out)?;
+			writeln!(out, "Playlist warnings:")?;
+			for warning in &self.playlist_warnings {
+				writeln!(out, "  {}", warning)?;
+			}
+		}
+
+		Ok(())
+	}
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+	const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+
+	let mut value = bytes as f64;
+	let mut unit = 0;
+	while value >= 1024.0 && unit < UNITS.len() - 1 {
+		value /= 1024.0;
+		unit += 1;
+	}
+
+	if unit == 0 {
+		format!("{} {}", bytes, UNITS[0])
+	} else {
+		format!("{:.2} {}", value, UNITS[unit])
+	}
+}
+
+pub fn format_duration(duration: Duration) -> String {
+	let secs = duration.as_secs();
+	format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn formatting() {
+		assert_eq!(format_bytes(1000), "1000 B");
+		assert_eq!(format_bytes(1536), "1.50 KiB");
+		assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.00 GiB");
+		assert_eq!(format_duration(Duration::from_secs(3725)), "1:02:05");
+	}
+
+	#[test]
+	fn nothing_printed_before_start() {
+		let mut out = Vec::new();
+		Summary::default().print(&mut out, Duration::ZERO);
+		assert!(out.is_empty());
+	}
+}
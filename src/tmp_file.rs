@@ -0,0 +1,219 @@
+use anyhow::{Context, Result};
+#[cfg(target_os = "linux")]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{
+	io,
+	path::{Path, PathBuf},
+};
+
+const SUFFIX: &str = ".audio-conv-tmp";
+
+/// Temporary file the output gets written to before it's renamed to the actual file name
+///
+/// The file name contains the PID of the process writing it, so files left behind by killed
+/// processes can be told apart from the ones of a still running process. The file gets removed
+/// when the `TmpFile` is dropped, e.g. if the transcode was cancelled.
+pub struct TmpFile {
+	path: PathBuf,
+}
+
+impl TmpFile {
+	pub fn new(to_path: &Path) -> TmpFile {
+		let mut file_name = to_path
+			.file_name()
+			.map(ToOwned::to_owned)
+			.unwrap_or_default();
+		file_name.push(format!(".{}{}", std::process::id(), SUFFIX));
+
+		TmpFile {
+			path: to_path.with_file_name(file_name),
+		}
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+}
+
+impl Drop for TmpFile {
+	fn drop(&mut self) {
+		// the file is usually already renamed or removed at this point, so errors are expected
+		let _ = std::fs::remove_file(&self.path);
+	}
+}
+
+/// Removes temporary files in the "to" dir `dir` that were left behind by processes that aren't
+/// running anymore, returns the number of removed files
+///
+/// Only files named like a `TmpFile` are considered, everything else in `dir` is left alone.
+pub fn remove_stale(dir: &Path) -> Result<usize> {
+	let mut removed = 0;
+
+	for entry in walkdir::WalkDir::new(dir) {
+		let entry = match entry {
+			Ok(entry) => entry,
+			// "to" doesn't exist before the first run, and files can vanish while walking
+			Err(err) if err.io_error().map(io::Error::kind) == Some(io::ErrorKind::NotFound) => {
+				continue;
+			}
+			Err(err) => {
+				let path = err.path().unwrap_or(dir).to_owned();
+				return Err(err).with_context(|| {
+					format!(
+						"Could not look for stale temporary files in {}",
+						path.display()
+					)
+				});
+			}
+		};
+		if !entry.file_type().is_file() {
+			continue;
+		}
+
+		let Some(pid) = tmp_file_pid(entry.file_name().to_string_lossy().as_ref()) else {
+			continue;
+		};
+		if is_writer_running(pid, entry.path()) {
+			continue;
+		}
+
+		match std::fs::remove_file(entry.path()) {
+			Ok(()) => removed += 1,
+			Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+			Err(err) => {
+				return Err(err).with_context(|| {
+					format!(
+						"Could not remove stale temporary file {}",
+						entry.path().display()
+					)
+				});
+			}
+		}
+	}
+
+	Ok(removed)
+}
+
+/// The PID in the file name of a `TmpFile`, `None` for files with other names
+fn tmp_file_pid(file_name: &str) -> Option<u32> {
+	let (output_name, pid) = file_name.strip_suffix(SUFFIX)?.rsplit_once('.')?;
+	if output_name.is_empty() || !pid.bytes().all(|b| b.is_ascii_digit()) {
+		return None;
+	}
+	pid.parse().ok()
+}
+
+/// Whether the process `pid` is running and is the one that wrote `path`, and not another one
+/// that got the same PID later on
+fn is_writer_running(pid: u32, path: &Path) -> bool {
+	if !is_process_running(pid) {
+		return false;
+	}
+
+	// the files of a running process were written after it started, the start time is only
+	// known to the second, so some slack is added
+	#[cfg(target_os = "linux")]
+	{
+		let process_start = process_start_time(pid);
+		let file_mtime = std::fs::metadata(path).and_then(|md| md.modified()).ok();
+		if let (Some(process_start), Some(file_mtime)) = (process_start, file_mtime) {
+			return file_mtime + Duration::from_secs(2) >= process_start;
+		}
+	}
+
+	#[cfg(not(target_os = "linux"))]
+	let _ = path;
+
+	true
+}
+
+#[cfg(target_os = "linux")]
+fn process_start_time(pid: u32) -> Option<SystemTime> {
+	let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+	// the fields following the command name, which is in parentheses and can contain spaces
+	let (_, fields) = stat.rsplit_once(')')?;
+	// in clock ticks since boot, the 22nd field
+	let start_ticks: u64 = fields.split_whitespace().nth(19)?.parse().ok()?;
+
+	let boot_time: u64 = std::fs::read_to_string("/proc/stat")
+		.ok()?
+		.lines()
+		.find_map(|line| line.strip_prefix("btime "))?
+		.trim()
+		.parse()
+		.ok()?;
+
+	// SAFETY: only reads a system configuration value
+	let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+	if ticks_per_sec <= 0 {
+		return None;
+	}
+
+	Some(
+		UNIX_EPOCH
+			+ Duration::from_secs(boot_time)
+			+ Duration::from_secs_f64(start_ticks as f64 / ticks_per_sec as f64),
+	)
+}
+
+fn is_process_running(pid: u32) -> bool {
+	#[cfg(unix)]
+	{
+		let pid = match libc::pid_t::try_from(pid) {
+			Ok(pid) => pid,
+			Err(_) => return false,
+		};
+
+		// SAFETY: signal `0` only checks whether the process exists, nothing gets sent
+		let res = unsafe { libc::kill(pid, 0) };
+		res == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+	}
+
+	#[cfg(not(unix))]
+	{
+		pid == std::process::id()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn pid_of_tmp_file() {
+		assert_eq!(tmp_file_pid("01.opus.1234.audio-conv-tmp"), Some(1234));
+		assert_eq!(tmp_file_pid("01.opus.audio-conv-tmp"), None);
+		assert_eq!(tmp_file_pid("01.opus.tmp"), None);
+		assert_eq!(tmp_file_pid("01.opus.+12.audio-conv-tmp"), None);
+		assert_eq!(tmp_file_pid(".12.audio-conv-tmp"), None);
+		assert_eq!(tmp_file_pid("01.tmp"), None);
+	}
+
+	#[test]
+	fn only_stale_tmp_files_are_removed() {
+		let dir = std::env::temp_dir().join(format!("audio-conv-test-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let own = TmpFile::new(&dir.join("a.opus"));
+		// not a valid PID, so no process with it is running
+		let stale = dir.join("b.opus.4000000000.audio-conv-tmp");
+		let copied = dir.join("c.tmp");
+		for path in [own.path(), &stale, &copied] {
+			std::fs::write(path, b"").unwrap();
+		}
+
+		assert_eq!(remove_stale(&dir).unwrap(), 1);
+		assert!(own.path().exists());
+		assert!(!stale.exists());
+		assert!(copied.exists());
+		assert_eq!(remove_stale(&dir.join("missing")).unwrap(), 0);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn own_tmp_file_is_not_stale() {
+		let tmp_file = TmpFile::new(&std::env::temp_dir().join("audio-conv-test.opus"));
+		std::fs::write(tmp_file.path(), b"").unwrap();
+		assert!(is_writer_running(std::process::id(), tmp_file.path()));
+	}
+}
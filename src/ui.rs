@@ -1,23 +1,83 @@
-use crate::ConversionArgs;
+use crate::{
+	ConversionArgs,
+	config::{Config, Progress},
+	control::Command,
+	events::EventWriter,
+	summary::{format_bytes, format_duration},
+};
 use anyhow::{Context, Result};
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyModifiers};
 use futures::Future;
 use std::{
-	borrow::Cow, cell::RefCell, collections::HashMap, io, mem, path::PathBuf, rc::Rc,
-	time::Duration,
+	cell::RefCell,
+	collections::HashMap,
+	fmt,
+	io::{self, IsTerminal},
+	mem,
+	path::PathBuf,
+	rc::Rc,
+	time::{Duration, Instant},
+};
+use tokio::{
+	sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+	task,
+	time::interval,
 };
-use tokio::{task, time::interval};
 use tui::{Terminal, backend::CrosstermBackend};
 
 pub const UPDATE_INTERVAL_MILLIS: u64 = 100;
 
+// how often the overall progress is printed in the plain progress mode
+const PLAIN_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
 #[derive(Debug)]
 pub enum Msg {
-	Init { task_len: usize, log_path: PathBuf },
+	Init {
+		task_len: usize,
+		/// sum of the sizes of all "from" files in bytes
+		total_size: u64,
+		/// number of paths that couldn't be read
+		scan_errors: usize,
+		log_path: PathBuf,
+	},
+	TasksAdded {
+		task_len: usize,
+		total_size: u64,
+	},
 	Exit,
-	TaskStart { id: usize, args: ConversionArgs },
-	TaskEnd { id: usize },
-	TaskProgress { id: usize, ratio: f64 },
-	TaskError { id: usize },
+	TaskStart {
+		id: usize,
+		args: ConversionArgs,
+	},
+	TaskEnd {
+		id: usize,
+		output_size: u64,
+		duration: Duration,
+	},
+	TaskProgress {
+		id: usize,
+		ratio: f64,
+		/// position in the audio stream
+		position: Duration,
+	},
+	TaskError {
+		id: usize,
+		/// the error message followed by its causes
+		errors: Vec<String>,
+	},
+	TaskSkipped {
+		id: usize,
+	},
+	/// a path that couldn't be read, found in watch mode after the initial scan
+	ScanError {
+		/// the error message followed by its causes
+		errors: Vec<String>,
+	},
+	SchedulerState {
+		jobs: usize,
+		paused: bool,
+		stopping: bool,
+	},
 }
 
 #[derive(Debug, Clone)]
@@ -43,75 +103,373 @@ impl MsgQueue {
 }
 
 struct State {
-	terminal: Terminal<CrosstermBackend<io::Stdout>>,
+	progress: Progress,
+	// only set in the `Tui` progress mode
+	terminal: Option<Terminal<CrosstermBackend<io::Stdout>>>,
+	last_plain_progress: Instant,
+	events: Option<EventWriter>,
+	commands: UnboundedSender<Command>,
 	log_path: Option<PathBuf>,
+	// number of paths that couldn't be read while looking for files to convert
+	scan_errors: usize,
 	task_len: Option<usize>,
 	ended_tasks: usize,
+	// set as soon as the planning is done and the tasks get started
+	started: Option<Instant>,
+	total_size: u64,
+	// sizes of the ended tasks, including failed and skipped ones
+	ended_size: u64,
+	// sizes and audio durations of the successfully converted tasks
+	converted_size: u64,
+	converted_audio: Duration,
 	running_tasks: HashMap<usize, Task>,
+	selected_task: Option<usize>,
+	scheduler_state: Option<SchedulerState>,
 	has_rendered: bool,
-	has_errored: bool,
+	failed_tasks: Vec<FailedTask>,
+	// index of the first failed task shown in the errors pane
+	errors_scroll: usize,
+}
+
+#[derive(Debug)]
+struct FailedTask {
+	rel_from_path: PathBuf,
+	/// the root cause of the error, the full chain is in the log file
+	error: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SchedulerState {
+	jobs: usize,
+	paused: bool,
+	stopping: bool,
 }
 
 impl State {
-	fn new() -> Result<State> {
-		let terminal = Terminal::new(CrosstermBackend::new(io::stdout()))
-			.context("Unable to create ui terminal")?;
+	fn new(
+		progress: Progress,
+		events: Option<EventWriter>,
+		commands: UnboundedSender<Command>,
+	) -> Result<State> {
+		let terminal = match progress {
+			Progress::Tui => {
+				let terminal = Terminal::new(CrosstermBackend::new(io::stdout()))
+					.context("Unable to create ui terminal")?;
+
+				// needed to receive single key presses
+				crossterm::terminal::enable_raw_mode().context("Unable to enable raw mode")?;
+
+				Some(terminal)
+			}
+			Progress::Plain | Progress::None => None,
+		};
 
 		Ok(State {
+			progress,
 			terminal,
+			last_plain_progress: Instant::now(),
+			events,
+			commands,
 			log_path: None,
+			scan_errors: 0,
 			task_len: None,
 			ended_tasks: 0,
+			started: None,
+			total_size: 0,
+			ended_size: 0,
+			converted_size: 0,
+			converted_audio: Duration::ZERO,
 			running_tasks: HashMap::new(),
+			selected_task: None,
+			scheduler_state: None,
 			has_rendered: false,
-			has_errored: false,
+			failed_tasks: Vec::new(),
+			errors_scroll: 0,
 		})
 	}
 
+	fn process_input(&mut self) -> Result<()> {
+		if self.terminal.is_none() {
+			return Ok(());
+		}
+
+		while crossterm::event::poll(Duration::ZERO).context("Polling input failed")? {
+			if let Event::Key(key) = crossterm::event::read().context("Reading input failed")? {
+				self.process_key(key);
+			}
+		}
+
+		Ok(())
+	}
+
+	fn process_key(&mut self, key: KeyEvent) {
+		let command = match key.code {
+			KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+				Some(Command::Abort)
+			}
+			KeyCode::Char('q') => Some(Command::Stop),
+			KeyCode::Char('p') => Some(Command::TogglePause),
+			KeyCode::Char('+') => Some(Command::IncreaseJobs),
+			KeyCode::Char('-') => Some(Command::DecreaseJobs),
+			KeyCode::Char('s') => self.selected_task().map(|id| Command::Skip { id }),
+			KeyCode::Up => {
+				self.move_selection(-1);
+				None
+			}
+			KeyCode::Down => {
+				self.move_selection(1);
+				None
+			}
+			KeyCode::PageUp => {
+				self.errors_scroll = self.errors_scroll.saturating_sub(1);
+				None
+			}
+			KeyCode::PageDown => {
+				self.errors_scroll =
+					(self.errors_scroll + 1).min(self.failed_tasks.len().saturating_sub(1));
+				None
+			}
+			_ => None,
+		};
+
+		if let Some(command) = command {
+			// the scheduler is already gone if sending fails, nothing left to control
+			let _ = self.commands.send(command);
+		}
+	}
+
+	fn sorted_task_ids(&self) -> Vec<usize> {
+		let mut ids: Vec<_> = self.running_tasks.keys().copied().collect();
+		ids.sort_unstable();
+		ids
+	}
+
+	/// The selected task, falls back to the first one if the selected one isn't running anymore
+	fn selected_task(&self) -> Option<usize> {
+		self.selected_task
+			.filter(|id| self.running_tasks.contains_key(id))
+			.or_else(|| self.sorted_task_ids().first().copied())
+	}
+
+	fn move_selection(&mut self, offset: isize) {
+		let ids = self.sorted_task_ids();
+		if ids.is_empty() {
+			return;
+		}
+
+		let pos = self
+			.selected_task()
+			.and_then(|id| ids.iter().position(|i| *i == id))
+			.unwrap_or(0);
+		let pos = pos.saturating_add_signed(offset).min(ids.len() - 1);
+		self.selected_task = Some(ids[pos]);
+	}
+
 	fn process_msg(&mut self, msg: Msg) -> Result<bool> {
+		if let Some(events) = &mut self.events {
+			events.write(&msg)?;
+		}
+
 		match msg {
-			Msg::Init { task_len, log_path } => {
+			Msg::Init {
+				task_len,
+				total_size,
+				scan_errors,
+				log_path,
+			} => {
+				if self.progress == Progress::Plain {
+					println!(
+						"[init] {} file(s) to convert, {}",
+						task_len,
+						format_bytes(total_size)
+					);
+					if scan_errors > 0 {
+						println!(
+							"[init] {} path(s) couldn't be read and were logged to {}",
+							scan_errors,
+							log_path.display()
+						);
+					}
+				}
 				self.task_len = Some(task_len);
+				self.total_size = total_size;
+				// watch mode might have found some before
+				self.scan_errors += scan_errors;
 				self.log_path = Some(log_path);
+				self.started = Some(Instant::now());
+			}
+			Msg::TasksAdded {
+				task_len,
+				total_size,
+			} => {
+				*self.task_len.get_or_insert(0) += task_len;
+				self.total_size += total_size;
 			}
 			Msg::Exit => return Ok(false),
 			Msg::TaskStart { id, args } => {
+				self.print_plain("start", &args);
 				self.running_tasks.insert(
 					id,
 					Task {
 						id,
 						ratio: None,
+						position: None,
+						start: Instant::now(),
 						args,
 					},
 				);
 			}
-			Msg::TaskEnd { id } => {
-				self.running_tasks
+			Msg::TaskEnd { id, .. } => {
+				let task = self
+					.running_tasks
 					.remove(&id)
 					.context("Unable to remove finished task; could't find task")?;
 				self.ended_tasks += 1;
+				self.ended_size += task.args.size;
+				self.converted_size += task.args.size;
+				self.converted_audio += task.position.unwrap_or_default();
+				self.print_plain("done", &task.args);
 			}
-			Msg::TaskProgress { id, ratio } => {
+			Msg::TaskProgress {
+				id,
+				ratio,
+				position,
+			} => {
 				let task = self
 					.running_tasks
 					.get_mut(&id)
 					.context("Unable to update task progress; could't find task")?;
 				task.ratio = Some(ratio);
+				task.position = Some(position);
 			}
-			Msg::TaskError { id } => {
-				// TODO
-				self.running_tasks
+			Msg::TaskError { id, errors } => {
+				let task = self
+					.running_tasks
 					.remove(&id)
 					.context("Unable to remove errored task; could't find task")?;
 				self.ended_tasks += 1;
-				self.has_errored = true;
+				self.ended_size += task.args.size;
+
+				let error = errors
+					.last()
+					.and_then(|error| error.lines().next())
+					.unwrap_or("unknown error")
+					.to_string();
+				if self.progress == Progress::Plain {
+					println!("[error] {}: {}", task.args.rel_from_path.display(), error);
+				}
+				self.failed_tasks.push(FailedTask {
+					rel_from_path: task.args.rel_from_path,
+					error,
+				});
+			}
+			Msg::TaskSkipped { id } => {
+				let task = self
+					.running_tasks
+					.remove(&id)
+					.context("Unable to remove skipped task; could't find task")?;
+				self.ended_tasks += 1;
+				self.ended_size += task.args.size;
+				self.print_plain("skip", &task.args);
+			}
+			Msg::ScanError { errors } => {
+				self.scan_errors += 1;
+				if self.progress == Progress::Plain {
+					println!("[error] {}", errors.join(": "));
+				}
+			}
+			Msg::SchedulerState {
+				jobs,
+				paused,
+				stopping,
+			} => {
+				self.scheduler_state = Some(SchedulerState {
+					jobs,
+					paused,
+					stopping,
+				});
 			}
 		}
 
 		Ok(true)
 	}
 
+	fn overall_progress(&self, task_len: usize) -> OverallProgress {
+		let running_size: f64 = self
+			.running_tasks
+			.values()
+			.map(|task| task.ratio.unwrap_or(0.0) * task.args.size as f64)
+			.sum();
+		let running_audio: Duration = self
+			.running_tasks
+			.values()
+			.filter_map(|task| task.position)
+			.sum();
+
+		// weighted by file size, which is a good enough estimate of the audio duration
+		let ratio = if self.total_size > 0 {
+			((self.ended_size as f64 + running_size) / self.total_size as f64).clamp(0.0, 1.0)
+		} else {
+			self.ended_tasks as f64 / task_len as f64
+		};
+
+		let rate = self.started.and_then(|started| {
+			Rate::new(
+				started.elapsed(),
+				self.converted_size as f64 + running_size,
+				self.converted_audio + running_audio,
+			)
+		});
+
+		let eta = rate.and_then(|rate| {
+			let remaining = self.total_size as f64 - self.ended_size as f64 - running_size;
+			(rate.bytes_per_sec > 0.0)
+				.then(|| Duration::from_secs_f64(remaining.max(0.0) / rate.bytes_per_sec))
+		});
+
+		OverallProgress { ratio, eta, rate }
+	}
+
+	fn print_plain(&self, event: &str, args: &ConversionArgs) {
+		if self.progress == Progress::Plain {
+			println!("[{}] {}", event, args.rel_from_path.display());
+		}
+	}
+
 	fn render(&mut self) -> Result<()> {
+		match self.progress {
+			Progress::Tui => self.render_tui(),
+			Progress::Plain => {
+				self.render_plain();
+				Ok(())
+			}
+			Progress::None => Ok(()),
+		}
+	}
+
+	fn render_plain(&mut self) {
+		let task_len = match self.task_len {
+			Some(task_len) if task_len > 0 => task_len,
+			_ => return,
+		};
+
+		if self.last_plain_progress.elapsed() < PLAIN_PROGRESS_INTERVAL {
+			return;
+		}
+		self.last_plain_progress = Instant::now();
+
+		let overall = self.overall_progress(task_len);
+		println!(
+			"[progress] {}/{} ({:.1}%), {} running{}",
+			self.ended_tasks,
+			task_len,
+			overall.ratio * 100.0,
+			self.running_tasks.len(),
+			overall.details()
+		);
+	}
+
+	fn render_tui(&mut self) -> Result<()> {
 		use tui::{
 			layout::{Constraint, Direction, Layout, Rect},
 			style::{Color, Modifier, Style},
@@ -129,33 +487,73 @@ impl State {
 			return Ok(());
 		}
 
-		let tasks_ended = self.ended_tasks;
+		let overall = self.overall_progress(task_len);
 
 		let mut running_tasks: Vec<_> = self.running_tasks.values().cloned().collect();
 
 		running_tasks.sort_by_key(|task| task.id);
 
-		if !self.has_rendered {
-			self.terminal.clear().context("Clearing ui failed")?;
-			self.has_rendered = true;
-		}
+		let selected_task = self.selected_task();
+
+		let status_text = self.scheduler_state.map(|state| {
+			let mut text = format!("jobs: {}", state.jobs);
+			if state.paused {
+				text.push_str(" | paused");
+			}
+			if state.stopping {
+				text.push_str(" | stopping, press q again to abort");
+			}
+			text.push_str(" | q: stop, p: pause, +/-: jobs, up/down: select, s: skip");
+			if !self.failed_tasks.is_empty() {
+				text.push_str(", pgup/pgdn: scroll errors");
+			}
+			text
+		});
+
+		let errors = if self.failed_tasks.is_empty() {
+			None
+		} else {
+			let title = match &self.log_path {
+				Some(log_path) => format!(
+					"Errors ({}), logged to {}",
+					self.failed_tasks.len(),
+					log_path.display()
+				),
+				None => format!("Errors ({})", self.failed_tasks.len()),
+			};
+			let text = self
+				.failed_tasks
+				.iter()
+				.skip(self.errors_scroll)
+				.map(|task| format!("{}: {}", task.rel_from_path.display(), task.error))
+				.collect::<Vec<_>>()
+				.join("\n");
+			Some((title, text, self.failed_tasks.len() - self.errors_scroll))
+		};
 
-		let error_text = match self.has_errored {
-			true => {
-				let text: Cow<'static, str> = self
-					.log_path
-					.as_ref()
-					.map(|lp| {
-						let text = format!("Error(s) occurred and were logged to {}", lp.display());
-						Cow::Owned(text)
-					})
-					.unwrap_or_else(|| Cow::Borrowed("Error(s) occurred"));
-				Some(text)
+		let overall_title = match (self.scan_errors, &self.log_path) {
+			(0, _) => String::from("Overall Progress"),
+			(scan_errors, Some(log_path)) => format!(
+				"Overall Progress, {} path(s) couldn't be read, logged to {}",
+				scan_errors,
+				log_path.display()
+			),
+			(scan_errors, None) => {
+				format!("Overall Progress, {} path(s) couldn't be read", scan_errors)
 			}
-			false => None,
 		};
 
-		self.terminal
+		let terminal = match &mut self.terminal {
+			Some(terminal) => terminal,
+			None => return Ok(()),
+		};
+
+		if !self.has_rendered {
+			terminal.clear().context("Clearing ui failed")?;
+			self.has_rendered = true;
+		}
+
+		terminal
 			.draw(|f| {
 				let chunks = Layout::default()
 					.direction(Direction::Vertical)
@@ -165,8 +563,37 @@ impl State {
 
 				let mut task_rect = chunks[0];
 
-				if error_text.is_some() {
-					task_rect.height -= 3;
+				if let Some(status_text) = status_text {
+					task_rect.height = task_rect.height.saturating_sub(1);
+					f.render_widget(
+						Paragraph::new(Text::raw(status_text))
+							.style(Style::default().fg(Color::White).bg(Color::Black)),
+						Rect::new(
+							task_rect.x,
+							task_rect.y + task_rect.height,
+							task_rect.width,
+							1,
+						),
+					);
+				}
+
+				if let Some((title, text, shown_errors)) = errors {
+					// the pane takes at most a third of the space, scrolling reveals the rest
+					let height = (shown_errors as u16 + 2)
+						.min((task_rect.height / 3).max(3))
+						.min(task_rect.height);
+					task_rect.height -= height;
+					f.render_widget(
+						Paragraph::new(Text::raw(text))
+							.block(Block::default().borders(Borders::ALL).title(title))
+							.style(Style::default().fg(Color::Red).bg(Color::Black)),
+						Rect::new(
+							task_rect.x,
+							task_rect.y + task_rect.height,
+							task_rect.width,
+							height,
+						),
+					);
 				}
 
 				for (row, task) in running_tasks
@@ -174,12 +601,28 @@ impl State {
 					.take(task_rect.height as usize / 2)
 					.enumerate()
 				{
+					let fg = if selected_task == Some(task.id) {
+						Color::Yellow
+					} else {
+						Color::White
+					};
+
+					let mut label = task.args.rel_from_path.to_string_lossy().into_owned();
+					let rate = Rate::new(
+						task.start.elapsed(),
+						task.ratio.unwrap_or(0.0) * task.args.size as f64,
+						task.position.unwrap_or_default(),
+					);
+					if let Some(rate) = rate {
+						label.push_str(&format!(" ({})", rate));
+					}
+
 					f.render_widget(
 						Gauge::default()
-							.label(task.args.rel_from_path.to_string_lossy().as_ref())
+							.label(label)
 							.gauge_style(
 								Style::default()
-									.fg(Color::White)
+									.fg(fg)
 									.bg(Color::Black)
 									.add_modifier(Modifier::ITALIC),
 							)
@@ -193,32 +636,21 @@ impl State {
 					);
 				}
 
-				if let Some(error_text) = error_text {
-					f.render_widget(
-						Paragraph::new(Text::raw(error_text)).style(
-							Style::default()
-								.fg(Color::Red)
-								.bg(Color::Black)
-								.add_modifier(Modifier::BOLD),
-						),
-						Rect::new(task_rect.x, task_rect.height + 1, task_rect.width, 2),
-					);
-				}
-
 				f.render_widget(
 					Gauge::default()
-						.block(
-							Block::default()
-								.borders(Borders::ALL)
-								.title("Overall Progress"),
-						)
+						.block(Block::default().borders(Borders::ALL).title(overall_title))
 						.gauge_style(
 							Style::default()
 								.fg(Color::White)
 								.bg(Color::Black)
 								.add_modifier(Modifier::ITALIC),
 						)
-						.ratio(tasks_ended as f64 / task_len as f64),
+						.label(format!(
+							"{:.1}%{}",
+							overall.ratio * 100.0,
+							overall.details()
+						))
+						.ratio(overall.ratio),
 					chunks[1],
 				);
 			})
@@ -228,20 +660,102 @@ impl State {
 	}
 }
 
+impl Drop for State {
+	fn drop(&mut self) {
+		if self.terminal.is_some() {
+			let _ = crossterm::terminal::disable_raw_mode();
+		}
+	}
+}
+
 #[derive(Debug, Clone)]
 struct Task {
 	id: usize,
 	ratio: Option<f64>,
+	position: Option<Duration>,
+	start: Instant,
 	args: ConversionArgs,
 }
 
-pub fn init() -> (MsgQueue, impl Future<Output = Result<()>>) {
+struct OverallProgress {
+	ratio: f64,
+	eta: Option<Duration>,
+	rate: Option<Rate>,
+}
+
+impl OverallProgress {
+	/// ETA and rate, prefixed with a comma, empty if they are not known yet
+	fn details(&self) -> String {
+		let mut details = String::new();
+		if let Some(eta) = self.eta {
+			details.push_str(&format!(", ETA {}", format_duration(eta)));
+		}
+		if let Some(rate) = self.rate {
+			details.push_str(&format!(", {}", rate));
+		}
+		details
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Rate {
+	bytes_per_sec: f64,
+	/// audio duration processed per second, "x times realtime"
+	speed: f64,
+}
+
+impl Rate {
+	/// `None` if not enough time has elapsed to give a meaningful estimate
+	fn new(elapsed: Duration, bytes: f64, audio: Duration) -> Option<Rate> {
+		let secs = elapsed.as_secs_f64();
+		if secs < 1.0 {
+			return None;
+		}
+
+		Some(Rate {
+			bytes_per_sec: bytes / secs,
+			speed: audio.as_secs_f64() / secs,
+		})
+	}
+}
+
+impl fmt::Display for Rate {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if self.speed > 0.0 {
+			write!(f, "{:.1}x, ", self.speed)?;
+		}
+		write!(f, "{}/s", format_bytes(self.bytes_per_sec as u64))
+	}
+}
+
+pub fn init(
+	config: &Config,
+) -> Result<(
+	MsgQueue,
+	UnboundedReceiver<Command>,
+	impl Future<Output = Result<()>> + use<>,
+)> {
 	let queue = MsgQueue::new();
+	let (command_sender, command_receiver) = unbounded_channel();
+
+	let events = config
+		.events
+		.as_ref()
+		.map(|output| EventWriter::new(config, output))
+		.transpose()?;
+
+	let progress = config.progress.unwrap_or_else(|| {
+		if io::stdout().is_terminal() {
+			Progress::Tui
+		} else {
+			Progress::Plain
+		}
+	});
 
 	let queue_clone = queue.clone();
 	let fut = async move {
 		let mut interval = interval(Duration::from_millis(UPDATE_INTERVAL_MILLIS));
-		let mut wrapped = Some((Vec::new(), State::new()?));
+		let mut wrapped = Some((Vec::new(), State::new(progress, events, command_sender)?));
 
 		loop {
 			interval.tick().await;
@@ -251,6 +765,8 @@ pub fn init() -> (MsgQueue, impl Future<Output = Result<()>>) {
 			queue_clone.swap_inner(&mut current_queue);
 
 			let render_res = task::spawn_blocking(move || -> Result<_> {
+				state.process_input()?;
+
 				let mut exit = false;
 				for msg in current_queue.drain(..) {
 					if !state.process_msg(msg)? {
@@ -279,5 +795,5 @@ pub fn init() -> (MsgQueue, impl Future<Output = Result<()>>) {
 		Result::<_>::Ok(())
 	};
 
-	(queue, fut)
+	Ok((queue, command_receiver, fut))
 }
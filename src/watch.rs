@@ -0,0 +1,425 @@
+use crate::{
+	Job, NameCollision,
+	config::{Config, DIR_CONFIG_FILE_NAME},
+	dir_config::DirConfigs,
+	error_log::ErrorLog,
+	get_file_job, get_jobs,
+	ignore_file::{IGNORE_FILE_NAME, IgnoreFiles},
+	is_skipped, report_scan_error,
+	summary::Summary,
+	ui, walk, walk_error,
+};
+use anyhow::{Context, Error, Result};
+use futures::prelude::*;
+use inotify::{EventMask, EventStream, Inotify, WatchDescriptor, WatchMask, Watches};
+use std::{
+	cell::RefCell,
+	collections::HashMap,
+	io,
+	path::{Path, PathBuf},
+	time::{Duration, Instant, SystemTime},
+};
+use tokio::{sync::mpsc::UnboundedSender, task, time::interval};
+
+// time a file needs to stay unchanged before it gets converted
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+const WATCH_MASK: WatchMask = WatchMask::ATTRIB
+	.union(WatchMask::CLOSE_WRITE)
+	.union(WatchMask::CREATE)
+	.union(WatchMask::DELETE)
+	.union(WatchMask::MODIFY)
+	.union(WatchMask::MOVED_FROM)
+	.union(WatchMask::MOVED_TO);
+
+/// The inotify watches on "from", installed before the initial scan so that no changes made during
+/// it are missed
+pub struct Watch<'a> {
+	watcher: Watcher<'a>,
+	events: EventStream<[u8; 4096]>,
+}
+
+impl<'a> Watch<'a> {
+	pub async fn new(
+		config: &'a Config,
+		jobs: UnboundedSender<Job>,
+		ui_queue: &'a ui::MsgQueue,
+		error_log: &'a ErrorLog,
+		summary: &'a RefCell<Summary>,
+	) -> Result<Watch<'a>> {
+		let inotify = Inotify::init().context("Could not initialize inotify")?;
+		let events = inotify
+			.into_event_stream([0u8; 4096])
+			.context("Could not create inotify event stream")?;
+
+		let mut watcher = Watcher {
+			config,
+			watches: events.watches(),
+			dirs: HashMap::new(),
+			pending_files: HashMap::new(),
+			pending_moves: HashMap::new(),
+			ignore_files: IgnoreFiles::default(),
+			jobs,
+			ui_queue,
+			error_log,
+			summary,
+		};
+		watcher.add_dir(&config.from, false).await?;
+
+		Ok(Watch { watcher, events })
+	}
+
+	/// Sends the jobs for the changes since the watches were installed, never returns on its own
+	pub async fn run(self) -> Result<()> {
+		let Watch {
+			mut watcher,
+			mut events,
+		} = self;
+		let mut settle_interval = interval(SETTLE_TIME / 4);
+
+		loop {
+			tokio::select! {
+				event = events.next() => {
+					let event = event
+						.context("inotify event stream ended")?
+						.context("Could not read inotify event")?;
+					watcher.process_event(event.wd, event.mask, event.cookie, event.name.as_deref()).await?;
+				}
+				_ = settle_interval.tick() => {
+					watcher.process_pending().await?;
+				}
+			}
+		}
+	}
+}
+
+struct Watcher<'a> {
+	config: &'a Config,
+	watches: Watches,
+	dirs: HashMap<WatchDescriptor, PathBuf>,
+	pending_files: HashMap<PathBuf, PendingFile>,
+	pending_moves: HashMap<u32, PendingMove>,
+	ignore_files: IgnoreFiles,
+	jobs: UnboundedSender<Job>,
+	ui_queue: &'a ui::MsgQueue,
+	error_log: &'a ErrorLog,
+	summary: &'a RefCell<Summary>,
+}
+
+struct PendingFile {
+	last_change: Instant,
+	stat: Option<(u64, SystemTime)>,
+}
+
+struct PendingMove {
+	path: PathBuf,
+	is_dir: bool,
+	time: Instant,
+}
+
+impl Watcher<'_> {
+	/// Watches `dir` and all of its sub dirs, optionally marking all files in them as changed
+	async fn add_dir(&mut self, dir: &Path, mark_files: bool) -> Result<()> {
+		// walking a big tree blocks
+		let config = self.config.clone();
+		let dir = dir.to_owned();
+		let (dirs, files, errors) = task::spawn_blocking(move || {
+			let mut dirs = Vec::new();
+			let mut files = Vec::new();
+			let mut errors = Vec::new();
+			for entry in walk(&config, &dir) {
+				match entry {
+					Ok(entry) if entry.file_type().is_dir() => dirs.push(entry.into_path()),
+					Ok(entry) if mark_files && entry.file_type().is_file() => {
+						files.push(entry.into_path())
+					}
+					Ok(_) => {}
+					Err(err) => errors.push(walk_error(err)),
+				}
+			}
+			(dirs, files, errors)
+		})
+		.await
+		.context("Watch task failed")?;
+
+		for err in errors {
+			self.report(err).await?;
+		}
+
+		for dir in dirs {
+			match self.watches.add(&dir, WATCH_MASK) {
+				Ok(wd) => {
+					self.dirs.insert(wd, dir);
+				}
+				// the other dirs are still watched, like when inotify runs out of watches
+				Err(err) => {
+					let err =
+						Error::new(err).context(format!("Could not watch dir {}", dir.display()));
+					self.report(err).await?;
+				}
+			}
+		}
+		for file in files {
+			self.mark_changed(file);
+		}
+
+		Ok(())
+	}
+
+	fn mark_changed(&mut self, path: PathBuf) {
+		self.pending_files.insert(
+			path,
+			PendingFile {
+				last_change: Instant::now(),
+				stat: None,
+			},
+		);
+	}
+
+	async fn process_event(
+		&mut self,
+		wd: WatchDescriptor,
+		mask: EventMask,
+		cookie: u32,
+		name: Option<&std::ffi::OsStr>,
+	) -> Result<()> {
+		if mask.contains(EventMask::Q_OVERFLOW) {
+			// we've lost events, so we need to look at everything again
+			return self.rescan().await;
+		}
+
+		if mask.contains(EventMask::IGNORED) {
+			self.dirs.remove(&wd);
+			return Ok(());
+		}
+
+		let path = match (self.dirs.get(&wd), name) {
+			(Some(dir), Some(name)) => dir.join(name),
+			// event for the watched dir itself or an already removed watch
+			_ => return Ok(()),
+		};
+		let is_dir = mask.contains(EventMask::ISDIR);
+
+		if path.file_name() == Some(IGNORE_FILE_NAME.as_ref()) {
+			// the changed rules only apply to files changed afterwards
+			if let Some(dir) = path.parent() {
+				self.ignore_files.invalidate(dir);
+			}
+			return Ok(());
+		}
+
+		if path.file_name() == Some(DIR_CONFIG_FILE_NAME.as_ref()) {
+			// the changed matches only apply to files changed afterwards
+			return Ok(());
+		}
+
+		if is_skipped(self.config, &mut self.ignore_files, &path, is_dir) {
+			return Ok(());
+		}
+
+		if mask.contains(EventMask::MOVED_FROM) {
+			self.pending_files.remove(&path);
+			self.pending_moves.insert(
+				cookie,
+				PendingMove {
+					path,
+					is_dir,
+					time: Instant::now(),
+				},
+			);
+		} else if mask.contains(EventMask::MOVED_TO) {
+			if let Some(pending_move) = self.pending_moves.remove(&cookie) {
+				if is_dir {
+					// the watches are following the moved dir, but we need to update the paths
+					for dir in self.dirs.values_mut() {
+						if let Ok(rel_path) = dir.strip_prefix(&pending_move.path) {
+							*dir = path.join(rel_path);
+						}
+					}
+				}
+
+				self.send(Job::Rename {
+					rel_from_path: self.rel_path(&pending_move.path)?,
+					rel_to_path: self.rel_path(&path)?,
+					is_dir,
+				})?;
+			}
+
+			if is_dir {
+				self.add_dir(&path, true).await?;
+			} else {
+				self.mark_changed(path);
+			}
+		} else if mask.contains(EventMask::DELETE) {
+			self.pending_files.remove(&path);
+			self.send(Job::Remove {
+				rel_from_path: self.rel_path(&path)?,
+				is_dir,
+			})?;
+		} else if is_dir {
+			if mask.contains(EventMask::CREATE) {
+				self.add_dir(&path, true).await?;
+			}
+		} else {
+			self.mark_changed(path);
+		}
+
+		Ok(())
+	}
+
+	async fn process_pending(&mut self) -> Result<()> {
+		let now = Instant::now();
+
+		let expired_moves: Vec<_> = self
+			.pending_moves
+			.iter()
+			.filter(|(_, m)| now.duration_since(m.time) >= SETTLE_TIME)
+			.map(|(cookie, _)| *cookie)
+			.collect();
+		for cookie in expired_moves {
+			// no matching `MOVED_TO` event, so the file or dir was moved out of "from"
+			let pending_move = self.pending_moves.remove(&cookie).unwrap();
+			self.send(Job::Remove {
+				rel_from_path: self.rel_path(&pending_move.path)?,
+				is_dir: pending_move.is_dir,
+			})?;
+		}
+
+		let mut settled = Vec::new();
+		self.pending_files.retain(|path, pending| {
+			if now.duration_since(pending.last_change) < SETTLE_TIME {
+				return true;
+			}
+
+			let stat = match path.metadata() {
+				Ok(md) => md.modified().ok().map(|mtime| (md.len(), mtime)),
+				// the file is gone or unreadable, nothing to do for us
+				Err(_) => return false,
+			};
+
+			if stat.is_some() && stat == pending.stat {
+				settled.push(path.clone());
+				false
+			} else {
+				// still being written, check again later
+				pending.stat = stat;
+				pending.last_change = now;
+				true
+			}
+		});
+
+		if settled.is_empty() {
+			return Ok(());
+		}
+
+		// the files might need to be probed, which blocks
+		let config = self.config.clone();
+		let jobs = task::spawn_blocking(move || {
+			let mut dir_configs = DirConfigs::default();
+			settled
+				.iter()
+				.filter_map(|path| get_file_job(&config, &mut dir_configs, path).transpose())
+				.collect::<Vec<_>>()
+		})
+		.await
+		.context("Watch task failed")?;
+		for job in jobs {
+			match job {
+				Ok(job) => self.send_job(job)?,
+				Err(err) => self.report(err).await?,
+			}
+		}
+
+		Ok(())
+	}
+
+	async fn rescan(&mut self) -> Result<()> {
+		let from = self.config.from.clone();
+		self.add_dir(&from, false).await?;
+
+		self.pending_files.clear();
+		let config = self.config.clone();
+		let jobs = task::spawn_blocking(move || get_jobs(&config).collect::<Vec<_>>())
+			.await
+			.context("Watch task failed")?;
+		for job in jobs {
+			match job {
+				Ok(job) => self.send_job(job)?,
+				Err(err) => self.report(err).await?,
+			}
+		}
+
+		Ok(())
+	}
+
+	fn rel_path(&self, path: &Path) -> Result<PathBuf> {
+		let rel_path = path.strip_prefix(&self.config.from).with_context(|| {
+			format!(
+				"Unable to get relative path for {} from {}",
+				path.display(),
+				self.config.from.display()
+			)
+		})?;
+		Ok(rel_path.to_owned())
+	}
+
+	/// Sends a job found by scanning, conversions are announced to the ui as new tasks
+	fn send_job(&self, job: Job) -> Result<()> {
+		if let Job::UpToDate = job {
+			return Ok(());
+		}
+		if let Job::Convert(args) = &job {
+			self.ui_queue.push(ui::Msg::TasksAdded {
+				task_len: 1,
+				total_size: args.size,
+			});
+		}
+		self.send(job)
+	}
+
+	/// Reports an error found while scanning the way the initial scan does, errors for paths that
+	/// were removed in the meantime are ignored
+	async fn report(&self, err: Error) -> Result<()> {
+		if is_not_found(&err) {
+			return Ok(());
+		}
+		if !err.is::<NameCollision>() {
+			self.ui_queue.push(ui::Msg::ScanError {
+				errors: err.chain().map(|e| e.to_string()).collect(),
+			});
+		}
+		report_scan_error(err, self.summary, self.error_log).await
+	}
+
+	fn send(&self, job: Job) -> Result<()> {
+		self.jobs
+			.send(job)
+			.map_err(|_| Error::msg("Job queue was closed"))
+	}
+}
+
+/// Whether `err` was caused by a path that doesn't exist
+fn is_not_found(err: &Error) -> bool {
+	err.chain().any(|cause| {
+		cause
+			.downcast_ref::<io::Error>()
+			.is_some_and(|err| err.kind() == io::ErrorKind::NotFound)
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn only_vanished_paths_are_ignored() {
+		let vanished = Error::new(io::Error::from(io::ErrorKind::NotFound))
+			.context("Unable to get metadata for \"from\" file /music/a.flac");
+		assert!(is_not_found(&vanished));
+
+		let unreadable = Error::new(io::Error::from(io::ErrorKind::PermissionDenied))
+			.context("Unable to read /music/a");
+		assert!(!is_not_found(&unreadable));
+		assert!(!is_not_found(&Error::msg("Could not parse config file")));
+	}
+}